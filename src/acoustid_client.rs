@@ -0,0 +1,30 @@
+use anyhow::Result;
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use reqwest::{Client, RequestBuilder, Response};
+use std::num::NonZeroU32;
+
+pub const DEFAULT_ACOUSTID_URL: &str = "https://api.acoustid.org/v2/lookup";
+
+pub struct AcoustIdClient {
+    pub request_client: Client,
+    pub api_key: String,
+    pub lookup_url: String,
+    pub rate_limiter: DefaultDirectRateLimiter,
+}
+
+impl AcoustIdClient {
+    pub fn new(api_key: String, lookup_url: String) -> Self {
+        AcoustIdClient {
+            request_client: Client::new(),
+            api_key,
+            lookup_url,
+            // AcoustID asks for no more than three requests per second
+            rate_limiter: RateLimiter::direct(Quota::per_second(NonZeroU32::new(3).unwrap())),
+        }
+    }
+
+    pub async fn take_request_builder(&self, request_builder: RequestBuilder) -> Result<Response> {
+        self.rate_limiter.until_ready().await;
+        Ok(request_builder.send().await?)
+    }
+}
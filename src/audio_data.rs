@@ -2,27 +2,44 @@ use crate::listenbrainz_client::ListenbrainzClient;
 use anyhow::{anyhow, Result};
 use audiotags::Tag;
 use cached::proc_macro::cached;
-use lofty::{file::TaggedFileExt, tag::ItemKey};
+use lofty::{file::TaggedFileExt, tag::Accessor, tag::ItemKey};
 use log::debug;
 use musicbrainz_rs::entity::artist::{Artist, ArtistSearchQuery};
 use musicbrainz_rs::Search;
-use serde_json::Value;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use url::Url;
 use uuid::Uuid;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub struct AudioFileData {
     pub artist: String,
     pub title: String,
     pub album: Option<String>,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct FingerprintData {
+    pub fingerprint: String,
+    pub duration: u32,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub enum AudioIDData {
     Mbid(Uuid),
     AudioFileData(AudioFileData),
+    Fingerprint(FingerprintData),
+    // Guessed from the file path, so less trustworthy than tags
+    InferredFileData(AudioFileData),
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ResolvedSong {
+    pub file_path: PathBuf,
+    pub mbid: Uuid,
+    pub source: AudioIDData,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
@@ -32,7 +49,7 @@ pub struct ArtistData {
 }
 
 pub async fn get_musicbrainz_id_for_audio_data(
-    listenbrainz_client: &mut ListenbrainzClient,
+    listenbrainz_client: &ListenbrainzClient,
     audio_file_data: AudioFileData,
 ) -> Result<Uuid> {
     let mut result = make_listenbrainz_lookup_request(
@@ -67,7 +84,7 @@ pub async fn get_musicbrainz_id_for_audio_data(
 }
 
 async fn make_listenbrainz_lookup_request(
-    listenbrainz_client: &mut ListenbrainzClient,
+    listenbrainz_client: &ListenbrainzClient,
     title: &String,
     artist: &String,
 ) -> Result<Value> {
@@ -84,8 +101,72 @@ async fn make_listenbrainz_lookup_request(
     Ok(result)
 }
 
+/// Looks up many songs in one request, giving `None` for any that could not be matched. Only the
+/// artist and title are sent, the same as for a single song, so both match the same recordings.
+pub async fn get_musicbrainz_ids_for_audio_data_batch(
+    listenbrainz_client: &ListenbrainzClient,
+    audio_file_data: &[AudioFileData],
+) -> Result<Vec<Option<Uuid>>> {
+    lookup_batch(
+        listenbrainz_client,
+        "https://api.listenbrainz.org/1/metadata/lookup/",
+        audio_file_data,
+    )
+    .await
+}
+
+async fn lookup_batch(
+    listenbrainz_client: &ListenbrainzClient,
+    lookup_url: &str,
+    audio_file_data: &[AudioFileData],
+) -> Result<Vec<Option<Uuid>>> {
+    let recordings: Vec<Value> = audio_file_data
+        .iter()
+        .map(|d| {
+            json!({
+                "artist_name": d.artist,
+                "recording_name": d.title,
+            })
+        })
+        .collect();
+    let result = listenbrainz_client
+        .take_request_builder(
+            listenbrainz_client
+                .request_client
+                .post(lookup_url)
+                .json(&json!({ "recordings": recordings })),
+        )
+        .await?
+        .error_for_status()?
+        .json::<Value>()
+        .await?;
+    Ok(parse_batch_lookup_response(&result, audio_file_data.len()))
+}
+
+fn parse_batch_lookup_response(response: &Value, number_of_requests: usize) -> Vec<Option<Uuid>> {
+    let mut mbids = vec![None; number_of_requests];
+    let Some(results) = response.as_array() else {
+        return mbids;
+    };
+    for result in results {
+        // Unmatched songs may be left out, so a result without an index can't be placed
+        let Some(index) = result.get("index").and_then(|i| i.as_u64()) else {
+            debug!("Ignoring lookup result without an index: {}", result);
+            continue;
+        };
+        let mbid = result
+            .get("recording_mbid")
+            .and_then(|m| m.as_str())
+            .and_then(|m| Uuid::from_str(m).ok());
+        if let Some(slot) = mbids.get_mut(index as usize) {
+            *slot = mbid;
+        }
+    }
+    mbids
+}
+
 #[cached]
-async fn get_artist_mbid(artist_name: String) -> ArtistData {
+pub async fn get_artist_mbid(artist_name: String) -> ArtistData {
     let query = ArtistSearchQuery::query_builder()
         .artist(artist_name.as_str())
         .build();
@@ -139,15 +220,15 @@ pub fn load_tags_from_file_path(file: PathBuf) -> Result<AudioIDData> {
         .title()
         .ok_or(anyhow!("Could not read title"))?
         .parse()?;
+    // The album only helps a lookup, so like the library index it isn't needed
     let album = tags
         .album()
-        .ok_or(anyhow!("Could not read album"))?
-        .title
-        .to_string();
+        .map(|a| a.title.to_string())
+        .filter(|a| !a.is_empty());
     Ok(AudioIDData::AudioFileData(AudioFileData {
         artist,
         title,
-        album: if album.is_empty() { None } else { Some(album) },
+        album,
     }))
 }
 
@@ -166,9 +247,42 @@ pub fn read_mbid_from_metadata(file: &PathBuf) -> Result<Uuid> {
     Err(anyhow!("Could not find MBID in tags"))
 }
 
+pub fn read_comment_from_metadata(file: &Path) -> Option<String> {
+    let file = lofty::read_from_path(file).ok()?;
+    let comment = file.primary_tag()?.comment()?.trim().to_string();
+    if comment.is_empty() {
+        None
+    } else {
+        Some(comment)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::tag_writer::test::make_test_mp3;
+    use lofty::config::WriteOptions;
+    use lofty::id3::v2::Id3v2Tag;
+    use lofty::tag::TagExt;
+
+    #[test]
+    fn test_load_tags_without_album() {
+        let path = make_test_mp3("no-album");
+        let mut tag = Id3v2Tag::default();
+        tag.set_artist("Tom Lehrer".to_string());
+        tag.set_title("The Elements".to_string());
+        tag.save_to_path(&path, WriteOptions::default()).unwrap();
+        assert_eq!(
+            load_tags_from_file_path(path.clone()).unwrap(),
+            AudioIDData::AudioFileData(AudioFileData {
+                artist: "Tom Lehrer".to_string(),
+                title: "The Elements".to_string(),
+                album: None,
+            })
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
     #[test]
     fn test_get_recording_mbid_general_1() {
         let test = AudioFileData {
@@ -176,10 +290,10 @@ mod test {
             title: "Perfect".parse().unwrap(),
             album: Some("Divide".to_string()),
         };
-        let mut test_client = ListenbrainzClient::new("".to_string());
+        let test_client = ListenbrainzClient::new("".to_string());
         let rt = tokio::runtime::Runtime::new().unwrap();
         let result = rt.block_on(async {
-            get_musicbrainz_id_for_audio_data(&mut test_client, test)
+            get_musicbrainz_id_for_audio_data(&test_client, test)
                 .await
                 .unwrap()
         });
@@ -193,10 +307,10 @@ mod test {
             title: "光あれ".parse().unwrap(),
             album: Some("光あれ".parse().unwrap()),
         };
-        let mut test_client = ListenbrainzClient::new("".to_string());
+        let test_client = ListenbrainzClient::new("".to_string());
         let rt = tokio::runtime::Runtime::new().unwrap();
         let result = rt.block_on(async {
-            get_musicbrainz_id_for_audio_data(&mut test_client, test)
+            get_musicbrainz_id_for_audio_data(&test_client, test)
                 .await
                 .unwrap()
         });
@@ -210,10 +324,10 @@ mod test {
             title: "Perfect Duet".parse().unwrap(),
             album: None,
         };
-        let mut test_client = ListenbrainzClient::new("".to_string());
+        let test_client = ListenbrainzClient::new("".to_string());
         let rt = tokio::runtime::Runtime::new().unwrap();
         let result = rt.block_on(async {
-            get_musicbrainz_id_for_audio_data(&mut test_client, test)
+            get_musicbrainz_id_for_audio_data(&test_client, test)
                 .await
                 .unwrap()
         });
@@ -227,10 +341,10 @@ mod test {
             title: "Never Let Me Go".parse().unwrap(),
             album: None,
         };
-        let mut test_client = ListenbrainzClient::new("".to_string());
+        let test_client = ListenbrainzClient::new("".to_string());
         let rt = tokio::runtime::Runtime::new().unwrap();
         let result = rt.block_on(async {
-            get_musicbrainz_id_for_audio_data(&mut test_client, test)
+            get_musicbrainz_id_for_audio_data(&test_client, test)
                 .await
                 .unwrap()
         });
@@ -244,10 +358,10 @@ mod test {
             title: "As I Am".parse().unwrap(),
             album: None,
         };
-        let mut test_client = ListenbrainzClient::new("".to_string());
+        let test_client = ListenbrainzClient::new("".to_string());
         let rt = tokio::runtime::Runtime::new().unwrap();
         let result = rt.block_on(async {
-            get_musicbrainz_id_for_audio_data(&mut test_client, test)
+            get_musicbrainz_id_for_audio_data(&test_client, test)
                 .await
                 .unwrap()
         });
@@ -261,10 +375,10 @@ mod test {
             title: "Dancing with Your Ghost".parse().unwrap(),
             album: None,
         };
-        let mut test_client = ListenbrainzClient::new("".to_string());
+        let test_client = ListenbrainzClient::new("".to_string());
         let rt = tokio::runtime::Runtime::new().unwrap();
         let result = rt.block_on(async {
-            get_musicbrainz_id_for_audio_data(&mut test_client, test)
+            get_musicbrainz_id_for_audio_data(&test_client, test)
                 .await
                 .unwrap()
         });
@@ -279,15 +393,80 @@ mod test {
             title: "Asdjkhfgds".parse().unwrap(),
             album: None,
         };
-        let mut test_client = ListenbrainzClient::new("".to_string());
+        let test_client = ListenbrainzClient::new("".to_string());
         let rt = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(async {
-            get_musicbrainz_id_for_audio_data(&mut test_client, test)
+            get_musicbrainz_id_for_audio_data(&test_client, test)
                 .await
                 .unwrap()
         });
     }
 
+    #[test]
+    fn test_parse_batch_lookup_response_with_index() {
+        let response = json!([
+            {"index": 2, "recording_mbid": "36855a5c-abcb-4740-9154-361af8c11ee1"},
+            {"index": 0, "recording_mbid": "00066722-b23a-48e5-82e4-0470c82a2705"},
+        ]);
+        let result = parse_batch_lookup_response(&response, 3);
+        assert_eq!(
+            result,
+            vec![
+                Some(Uuid::from_str("00066722-b23a-48e5-82e4-0470c82a2705").unwrap()),
+                None,
+                Some(Uuid::from_str("36855a5c-abcb-4740-9154-361af8c11ee1").unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lookup_batch_ignores_results_without_index() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut server = rt.block_on(mockito::Server::new_async());
+        let mock = server
+            .mock("POST", "/lookup")
+            .match_body(mockito::Matcher::Json(json!({"recordings": [
+                {"artist_name": "Ed Sheeran", "recording_name": "Perfect"},
+                {"artist_name": "Nobody", "recording_name": "Nothing"},
+                {"artist_name": "Miles Davis", "recording_name": "So What"},
+            ]})))
+            .with_body(
+                r#"[
+                    {"index": 2, "recording_mbid": "b84dd2d1-2bf1-4fcc-aadc-6cc39c36ba35"},
+                    {"recording_mbid": "00066722-b23a-48e5-82e4-0470c82a2705"},
+                    {"index": 0, "recording_mbid": "36855a5c-abcb-4740-9154-361af8c11ee1"}
+                ]"#,
+            )
+            .create();
+        let client = ListenbrainzClient::new("abc".to_string());
+        let songs = [
+            ("Ed Sheeran", "Perfect", Some("Divide")),
+            ("Nobody", "Nothing", None),
+            ("Miles Davis", "So What", Some("Kind of Blue")),
+        ]
+        .map(|(artist, title, album)| AudioFileData {
+            artist: artist.to_string(),
+            title: title.to_string(),
+            album: album.map(str::to_string),
+        });
+        let result = rt
+            .block_on(lookup_batch(
+                &client,
+                &format!("{}/lookup", server.url()),
+                &songs,
+            ))
+            .unwrap();
+        mock.assert();
+        assert_eq!(
+            result,
+            vec![
+                Some(Uuid::from_str("36855a5c-abcb-4740-9154-361af8c11ee1").unwrap()),
+                None,
+                Some(Uuid::from_str("b84dd2d1-2bf1-4fcc-aadc-6cc39c36ba35").unwrap()),
+            ]
+        );
+    }
+
     #[test]
     fn test_get_artist_mbid_1() {
         let test = "Ed Sheeran".to_string();
@@ -0,0 +1,287 @@
+use crate::audio_data::{AudioFileData, AudioIDData};
+use anyhow::{anyhow, Result};
+use log::{debug, error};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, OptionalExtension, Row};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::str::FromStr;
+use uuid::Uuid;
+
+// The fields beets searches for words in a query without a field
+const DEFAULT_SEARCH_FIELDS: [&str; 6] = [
+    "artist",
+    "albumartist",
+    "album",
+    "title",
+    "genre",
+    "comments",
+];
+const ITEM_COLUMNS: &str = "path, mb_trackid, artist, title, album";
+
+/// A track in a beets library
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BeetsItem {
+    pub path: PathBuf,
+    pub recording_mbid: Option<Uuid>,
+    pub artist: String,
+    pub title: String,
+    pub album: String,
+}
+
+impl BeetsItem {
+    /// Uses the MBID beets stored, or the artist and title when it matched nothing
+    pub fn audio_id_data(&self) -> AudioIDData {
+        match self.recording_mbid {
+            Some(mbid) => AudioIDData::Mbid(mbid),
+            None => AudioIDData::AudioFileData(AudioFileData {
+                artist: self.artist.clone(),
+                title: self.title.clone(),
+                album: Some(self.album.clone()).filter(|a| !a.is_empty()),
+            }),
+        }
+    }
+}
+
+/// Read-only access to the `library.db` of beets
+pub struct BeetsLibrary {
+    connection: Connection,
+    columns: HashSet<String>,
+}
+
+impl BeetsLibrary {
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let columns = connection
+            .prepare("SELECT name FROM pragma_table_info('items')")?
+            .query_map([], |r| r.get(0))?
+            .collect::<rusqlite::Result<HashSet<String>>>()?;
+        if columns.is_empty() {
+            return Err(anyhow!("{:?} is not a beets library", path));
+        }
+        Ok(BeetsLibrary {
+            connection,
+            columns,
+        })
+    }
+
+    /// Finds the items matching a beets query such as `genre:jazz year:2020..`, in album order
+    pub fn query(&self, query: &str) -> Result<Vec<BeetsItem>> {
+        let (condition, values) = query_to_sql(query, &self.columns)?;
+        debug!("Beets query '{}' became '{}'", query, condition);
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT {ITEM_COLUMNS} FROM items WHERE {condition} \
+            ORDER BY albumartist, album, disc, track"
+        ))?;
+        let items = statement
+            .query_map(params_from_iter(values), item_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(items)
+    }
+
+    pub fn find_by_path(&self, path: &Path) -> Option<BeetsItem> {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        self.connection
+            .query_row(
+                &format!("SELECT {ITEM_COLUMNS} FROM items WHERE path = ?1"),
+                params![path.as_os_str().as_encoded_bytes()],
+                item_from_row,
+            )
+            .optional()
+            .ok()
+            .flatten()
+    }
+}
+
+fn item_from_row(row: &Row) -> rusqlite::Result<BeetsItem> {
+    // Beets keeps paths as bytes, and empty strings for missing values
+    let path: Vec<u8> = row.get(0)?;
+    let text = |index: usize| -> rusqlite::Result<String> {
+        Ok(row.get::<_, Option<String>>(index)?.unwrap_or_default())
+    };
+    Ok(BeetsItem {
+        path: PathBuf::from(String::from_utf8_lossy(&path).to_string()),
+        recording_mbid: Uuid::from_str(&text(1)?).ok(),
+        artist: text(2)?,
+        title: text(3)?,
+        album: text(4)?,
+    })
+}
+
+/// Turns the parts of beets' query language that map onto SQL into a condition and its values.
+/// Fields that aren't columns are looked for in beets' flexible attributes.
+fn query_to_sql(query: &str, columns: &HashSet<String>) -> Result<(String, Vec<Value>)> {
+    let mut conditions = Vec::new();
+    let mut values = Vec::new();
+    for term in query.split_whitespace() {
+        let (negated, term) = match term.strip_prefix(['-', '^']) {
+            Some(rest) => (true, rest),
+            None => (false, term),
+        };
+        let condition = match term.split_once(':') {
+            None => {
+                let fields: Vec<&str> = DEFAULT_SEARCH_FIELDS
+                    .into_iter()
+                    .filter(|f| columns.contains(*f))
+                    .collect();
+                if fields.is_empty() {
+                    return Err(anyhow!(
+                        "The beets library has none of the fields '{}' is searched in",
+                        term
+                    ));
+                }
+                for _ in &fields {
+                    values.push(Value::Text(format!("%{term}%")));
+                }
+                let matches: Vec<String> = fields.iter().map(|f| format!("{f} LIKE ?")).collect();
+                format!("({})", matches.join(" OR "))
+            }
+            Some((_, value)) if value.starts_with(':') => {
+                return Err(anyhow!("Regular expression queries aren't supported"))
+            }
+            Some((field, value)) => {
+                if !field.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                    return Err(anyhow!("Invalid field '{}' in beets query", field));
+                }
+                let column = if columns.contains(field) {
+                    field.to_string()
+                } else {
+                    values.push(Value::Text(field.to_string()));
+                    "(SELECT value FROM item_attributes \
+                    WHERE entity_id = items.id AND key = ?)"
+                        .to_string()
+                };
+                match value.split_once("..") {
+                    Some((start, end)) => {
+                        let mut bounds = Vec::new();
+                        for (bound, operator) in [(start, ">="), (end, "<=")] {
+                            if bound.is_empty() {
+                                continue;
+                            }
+                            let number: f64 = bound
+                                .parse()
+                                .map_err(|_| anyhow!("Invalid range '{}' in beets query", value))?;
+                            if !bounds.is_empty() && !columns.contains(field) {
+                                values.push(Value::Text(field.to_string()));
+                            }
+                            bounds.push(format!("CAST({column} AS REAL) {operator} ?"));
+                            values.push(Value::Real(number));
+                        }
+                        if bounds.is_empty() {
+                            return Err(anyhow!("Invalid range '{}' in beets query", value));
+                        }
+                        format!("({})", bounds.join(" AND "))
+                    }
+                    None => {
+                        values.push(Value::Text(format!("%{value}%")));
+                        format!("{column} LIKE ?")
+                    }
+                }
+            }
+        };
+        conditions.push(if negated {
+            format!("NOT COALESCE({condition}, 0)")
+        } else {
+            condition
+        });
+    }
+    if conditions.is_empty() {
+        return Ok(("1".to_string(), values));
+    }
+    Ok((conditions.join(" AND "), values))
+}
+
+pub fn open_beets_library(path: &Path) -> BeetsLibrary {
+    BeetsLibrary::open(path).unwrap_or_else(|e| {
+        error!("Could not open beets library at {:?}: {}", path, e);
+        exit(1)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    fn make_test_library(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("lpu-{}-{}.db", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE items (id INTEGER PRIMARY KEY, path BLOB, title TEXT, artist TEXT,
+                    albumartist TEXT, album TEXT, genre TEXT, comments TEXT, year INTEGER,
+                    disc INTEGER, track INTEGER, mb_trackid TEXT);
+                CREATE TABLE item_attributes (id INTEGER PRIMARY KEY, entity_id INTEGER,
+                    key TEXT, value TEXT);
+                INSERT INTO items VALUES (1, CAST('/music/Miles Davis/So What.flac' AS BLOB),
+                    'So What', 'Miles Davis', 'Miles Davis', 'Kind of Blue', 'Jazz', '', 1959,
+                    1, 1, 'b84dd2d1-2bf1-4fcc-aadc-6cc39c36ba35');
+                INSERT INTO items VALUES (2, CAST('/music/Kamasi Washington/Street Fighter Mas.flac'
+                    AS BLOB), 'Street Fighter Mas', 'Kamasi Washington', 'Kamasi Washington',
+                    'Harmony of Difference', 'Jazz', '', 2017, 1, 2, '');
+                INSERT INTO items VALUES (3, CAST('/music/Ed Sheeran/Perfect.flac' AS BLOB),
+                    'Perfect', 'Ed Sheeran', 'Ed Sheeran', 'Divide', 'Pop', '', 2017, 1, 5,
+                    '36855a5c-abcb-4740-9154-361af8c11ee1');
+                INSERT INTO item_attributes VALUES (1, 1, 'mood', 'calm');",
+            )
+            .unwrap();
+        path
+    }
+
+    fn query_titles(library: &BeetsLibrary, query: &str) -> Vec<String> {
+        library
+            .query(query)
+            .unwrap()
+            .into_iter()
+            .map(|i| i.title)
+            .collect()
+    }
+
+    #[test]
+    fn test_beets_query() {
+        let path = make_test_library("beets-query");
+        let library = BeetsLibrary::open(&path).unwrap();
+        assert_eq!(query_titles(&library, "genre:jazz").len(), 2);
+        assert_eq!(
+            query_titles(&library, "genre:jazz year:2000.."),
+            vec!["Street Fighter Mas"]
+        );
+        assert_eq!(query_titles(&library, "-genre:jazz"), vec!["Perfect"]);
+        assert_eq!(query_titles(&library, "miles"), vec!["So What"]);
+        assert_eq!(query_titles(&library, "mood:calm"), vec!["So What"]);
+        assert_eq!(query_titles(&library, "").len(), 3);
+        assert!(library.query("title::^So").is_err());
+        assert!(library.query("year:abc..").is_err());
+        assert!(query_to_sql("miles", &HashSet::new()).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_beets_item_audio_id_data() {
+        let path = make_test_library("beets-items");
+        let library = BeetsLibrary::open(&path).unwrap();
+        let items = library.query("genre:jazz").unwrap();
+        assert_eq!(
+            items[0].audio_id_data(),
+            AudioIDData::AudioFileData(AudioFileData {
+                artist: "Kamasi Washington".to_string(),
+                title: "Street Fighter Mas".to_string(),
+                album: Some("Harmony of Difference".to_string()),
+            })
+        );
+        assert_eq!(
+            items[1].audio_id_data(),
+            AudioIDData::Mbid(Uuid::from_str("b84dd2d1-2bf1-4fcc-aadc-6cc39c36ba35").unwrap())
+        );
+        assert_eq!(
+            library
+                .find_by_path(Path::new("/music/Ed Sheeran/Perfect.flac"))
+                .unwrap()
+                .title,
+            "Perfect"
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
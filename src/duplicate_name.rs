@@ -0,0 +1,154 @@
+use anyhow::{anyhow, Result};
+use chrono::Local;
+
+pub const DEFAULT_DUPLICATE_NAME_TEMPLATE: &str = "{name}_{n}";
+
+pub struct DuplicateNameTemplate {
+    template: String,
+}
+
+pub struct TemplateValues {
+    pub name: String,
+    pub date: String,
+    pub hostname: String,
+    pub source: String,
+}
+
+impl TemplateValues {
+    pub fn new(name: &str, source: &str) -> Self {
+        TemplateValues {
+            name: name.to_string(),
+            date: Local::now().format("%Y-%m-%d").to_string(),
+            hostname: gethostname::gethostname().to_string_lossy().to_string(),
+            source: source.to_string(),
+        }
+    }
+}
+
+impl DuplicateNameTemplate {
+    /// Checks a template such as `{name} ({n})`, which can also use `{date}`, `{hostname}` and
+    /// `{source}`
+    pub fn new(template: &str) -> Result<Self> {
+        let mut remaining = template;
+        while let Some(start) = remaining.find('{') {
+            let end = remaining[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("Unclosed placeholder in template '{}'", template))?
+                + start;
+            match &remaining[start + 1..end] {
+                "name" | "n" | "date" | "hostname" | "source" => {}
+                other => return Err(anyhow!("Unknown placeholder '{{{}}}' in template", other)),
+            }
+            remaining = &remaining[end + 1..];
+        }
+        Ok(DuplicateNameTemplate {
+            template: template.to_string(),
+        })
+    }
+
+    // Each placeholder is filled in once, so values containing placeholders are left as they are
+    fn render(&self, values: &TemplateValues, n: usize) -> String {
+        let mut rendered = String::new();
+        let mut remaining = self.template.as_str();
+        while let Some(start) = remaining.find('{') {
+            let end = remaining[start..].find('}').expect("Templates are checked") + start;
+            rendered.push_str(&remaining[..start]);
+            match &remaining[start + 1..end] {
+                "name" => rendered.push_str(&values.name),
+                "date" => rendered.push_str(&values.date),
+                "hostname" => rendered.push_str(&values.hostname),
+                "source" => rendered.push_str(&values.source),
+                _ => rendered.push_str(&n.to_string()),
+            }
+            remaining = &remaining[end + 1..];
+        }
+        rendered.push_str(remaining);
+        rendered
+    }
+
+    /// Finds the first name that isn't taken, counting up from 1 for `{n}`. Templates without
+    /// `{n}` get a number appended if their name is taken too.
+    pub fn first_free_name(
+        &self,
+        values: &TemplateValues,
+        is_taken: impl Fn(&str) -> bool,
+    ) -> String {
+        let has_number = self.template.contains("{n}");
+        if !has_number {
+            let name = self.render(values, 0);
+            if !is_taken(&name) {
+                return name;
+            }
+        }
+        (1..)
+            .map(|n| {
+                if has_number {
+                    self.render(values, n)
+                } else {
+                    format!("{}_{}", self.render(values, n), n)
+                }
+            })
+            .find(|name| !is_taken(name))
+            .expect("There is always a free name")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_values() -> TemplateValues {
+        TemplateValues {
+            name: "Example".to_string(),
+            date: "2024-05-01".to_string(),
+            hostname: "desktop".to_string(),
+            source: "road_trip".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_first_free_number() {
+        let template = DuplicateNameTemplate::new("{name} ({n})").unwrap();
+        let taken = ["Example", "Example (1)", "Example (2)"];
+        let result = template.first_free_name(&test_values(), |n| taken.contains(&n));
+        assert_eq!(result, "Example (3)");
+    }
+
+    #[test]
+    fn test_default_template() {
+        let template = DuplicateNameTemplate::new(DEFAULT_DUPLICATE_NAME_TEMPLATE).unwrap();
+        let result = template.first_free_name(&test_values(), |n| n == "Example");
+        assert_eq!(result, "Example_1");
+    }
+
+    #[test]
+    fn test_template_without_number() {
+        let template =
+            DuplicateNameTemplate::new("{name} {date} from {source} on {hostname}").unwrap();
+        let result = template.first_free_name(&test_values(), |n| n == "Example");
+        assert_eq!(result, "Example 2024-05-01 from road_trip on desktop");
+
+        let result = template.first_free_name(&test_values(), |n| {
+            n == "Example 2024-05-01 from road_trip on desktop"
+        });
+        assert_eq!(result, "Example 2024-05-01 from road_trip on desktop_1");
+    }
+
+    #[test]
+    fn test_values_are_not_expanded() {
+        let template = DuplicateNameTemplate::new("{name} ({n})").unwrap();
+        let values = TemplateValues {
+            name: "Mix {n} {date}".to_string(),
+            ..test_values()
+        };
+        let taken = ["Mix {n} {date}", "Mix {n} {date} (1)"];
+        let result = template.first_free_name(&values, |n| taken.contains(&n));
+        assert_eq!(result, "Mix {n} {date} (2)");
+    }
+
+    #[test]
+    fn test_unknown_placeholder() {
+        assert!(DuplicateNameTemplate::new("{name} {nonsense}").is_err());
+        assert!(DuplicateNameTemplate::new("{name").is_err());
+    }
+}
@@ -0,0 +1,536 @@
+use crate::audio_data::AudioIDData;
+use crate::beets::open_beets_library;
+use crate::feedback::get_all_feedback;
+use crate::journal::Journal;
+use crate::library::{load_library_paths, name_key, open_library_index};
+use crate::listenbrainz_client::ListenbrainzClient;
+use crate::playlist::{PlaylistMetadata, PlaylistTrack};
+use crate::stats::Recording;
+use crate::upload::{
+    add_tracks_with_progress, resolve_all_songs_for_mbids, upload_playlist, UploadSettings,
+    UploadTarget,
+};
+use crate::{
+    home_path, resolve_user_name, stats, Args, Command, ExportFormat, ExportOutputArgs, Feedback,
+    FeedbackCommand, FeedbackExportArgs, RecommendationsArgs, TopRecordingsArgs,
+};
+use config::Config;
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::PathBuf;
+use std::process::exit;
+use url::Url;
+use uuid::Uuid;
+
+/// A recording to export, with the local file for it if there is one. Local files aren't always
+/// looked up, so may not have a recording.
+pub struct ExportEntry {
+    pub mbid: Option<Uuid>,
+    pub file: Option<PathBuf>,
+}
+
+pub fn to_m3u(entries: &[ExportEntry]) -> String {
+    let mut m3u = String::from("#EXTM3U\n");
+    for file in entries.iter().filter_map(|e| e.file.as_ref()) {
+        m3u.push_str(&file.to_string_lossy());
+        m3u.push('\n');
+    }
+    m3u
+}
+
+pub fn to_jspf(title: &str, entries: &[ExportEntry]) -> Value {
+    let tracks: Vec<Value> = entries
+        .iter()
+        .map(|e| {
+            let mut track = json!({});
+            if let Some(mbid) = e.mbid {
+                track["identifier"] = json!(format!("https://musicbrainz.org/recording/{}", mbid));
+            }
+            if let Some(location) = e.file.as_ref().and_then(|f| Url::from_file_path(f).ok()) {
+                track["location"] = json!([location.to_string()]);
+            }
+            track
+        })
+        .collect();
+    json!({"playlist": {"title": title, "track": tracks}})
+}
+
+pub fn to_csv(entries: &[ExportEntry]) -> String {
+    let mut csv = String::from("recording_mbid,file\n");
+    for entry in entries {
+        let file = entry
+            .file
+            .as_ref()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mbid = entry.mbid.map(|m| m.to_string()).unwrap_or_default();
+        csv.push_str(&format!("{},{}\n", mbid, escape_csv(&file)));
+    }
+    csv
+}
+
+fn escape_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+pub async fn export_top_recordings(
+    client: &ListenbrainzClient,
+    settings: &Config,
+    args: &Args,
+    upload: &UploadSettings,
+    top: &TopRecordingsArgs,
+) {
+    let user_name = match &top.user {
+        Some(u) => u.clone(),
+        None => resolve_user_name(client).await,
+    };
+    let recordings =
+        stats::get_top_recordings(client, &user_name, &top.range.api_name(), top.count.get())
+            .await
+            .unwrap_or_else(|e| {
+                error!("Could not get top recordings: {}", e);
+                exit(1)
+            });
+    info!("Found {} top recordings", recordings.len());
+    let entries = find_local_files(settings, args, &top.output, recordings);
+    let default_name = format!(
+        "Top recordings of {} ({})",
+        user_name,
+        top.range.api_name().replace('_', " ")
+    );
+    write_export(client, args, upload, &entries, &top.output, &default_name).await;
+}
+
+pub async fn export_recommendations(
+    client: &ListenbrainzClient,
+    settings: &Config,
+    args: &Args,
+    upload: &UploadSettings,
+    recommendations: &RecommendationsArgs,
+) {
+    let user_name = match &recommendations.user {
+        Some(u) => u.clone(),
+        None => resolve_user_name(client).await,
+    };
+    let recordings =
+        stats::get_recommended_recordings(client, &user_name, recommendations.count.get())
+            .await
+            .unwrap_or_else(|e| {
+                error!("Could not get recommendations: {}", e);
+                exit(1)
+            });
+    info!("Found {} recommended recordings", recordings.len());
+    let entries = find_local_files(settings, args, &recommendations.output, recordings);
+    let default_name = format!("Recommendations for {}", user_name);
+    write_export(
+        client,
+        args,
+        upload,
+        &entries,
+        &recommendations.output,
+        &default_name,
+    )
+    .await;
+}
+
+pub async fn export_feedback(
+    client: &ListenbrainzClient,
+    settings: &Config,
+    args: &Args,
+    upload: &UploadSettings,
+    export: &FeedbackExportArgs,
+) {
+    if export.score == Feedback::Neutral {
+        error!("Only loved or hated recordings can be exported");
+        exit(1)
+    }
+    let user_name = resolve_user_name(client).await;
+    let all_feedback = match get_all_feedback(client, &user_name).await {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Could not get existing feedback: {}", e);
+            exit(1)
+        }
+    };
+    let mut musicbrainz_ids: Vec<Uuid> = all_feedback
+        .into_iter()
+        .filter(|(_, f)| *f == export.score)
+        .map(|(mbid, _)| mbid)
+        .collect();
+    musicbrainz_ids.sort();
+    info!(
+        "Found {} recordings with {:?} feedback",
+        musicbrainz_ids.len(),
+        export.score
+    );
+    // Feedback only gives MBIDs, so files are only matched on their tagged MBID
+    let recordings = musicbrainz_ids
+        .into_iter()
+        .map(|mbid| Recording {
+            mbid,
+            artist: None,
+            title: None,
+        })
+        .collect();
+    let mut entries = find_local_files(settings, args, &export.output, recordings);
+    // Local files come first, in path order
+    entries.sort_by(|a, b| (a.file.is_none(), &a.file).cmp(&(b.file.is_none(), &b.file)));
+    let default_name = match export.score {
+        Feedback::Hate => "Hated tracks",
+        _ => "Loved tracks",
+    };
+    write_export(client, args, upload, &entries, &export.output, default_name).await;
+}
+
+/// Pairs recordings with their files in the music library, unless they're only being uploaded
+pub fn find_local_files(
+    settings: &Config,
+    args: &Args,
+    output: &ExportOutputArgs,
+    recordings: Vec<Recording>,
+) -> Vec<ExportEntry> {
+    if output.format == ExportFormat::Playlist || recordings.is_empty() {
+        return recordings
+            .into_iter()
+            .map(|r| ExportEntry {
+                mbid: Some(r.mbid),
+                file: None,
+            })
+            .collect();
+    }
+    let mut library_index = open_library_index(settings, args);
+    let library_paths = load_library_paths(settings, &output.libraries);
+    if !library_paths.is_empty() {
+        if let Err(e) = library_index.scan(&library_paths) {
+            error!("Could not scan the music library: {}", e);
+            exit(1)
+        }
+    }
+    let (library, untagged_library) = library_index
+        .recording_files()
+        .and_then(|l| Ok((l, library_index.untagged_files_by_name()?)))
+        .unwrap_or_else(|e| {
+            error!("Could not read the library index: {}", e);
+            exit(1)
+        });
+    let library_is_empty = library.is_empty() && untagged_library.is_empty();
+    if library_is_empty && output.format == ExportFormat::M3u {
+        error!("Exporting to M3U needs a music library, run library scan or give --library");
+        exit(1)
+    }
+    let entries: Vec<ExportEntry> = recordings
+        .into_iter()
+        .map(|r| {
+            // Files without a recording MBID in their tags are matched on artist and title
+            let file = library
+                .get(&r.mbid)
+                .or_else(|| match (&r.artist, &r.title) {
+                    (Some(artist), Some(title)) => untagged_library.get(&name_key(artist, title)),
+                    _ => None,
+                });
+            ExportEntry {
+                mbid: Some(r.mbid),
+                file: file.cloned(),
+            }
+        })
+        .collect();
+    let missing = entries.iter().filter(|e| e.file.is_none()).count();
+    if missing > 0 && !library_is_empty {
+        warn!(
+            "{}/{} recordings have no file in the library",
+            missing,
+            entries.len()
+        );
+    }
+    entries
+}
+
+pub async fn export_beets_query(
+    client: &ListenbrainzClient,
+    settings: &Config,
+    args: &Args,
+    upload: &UploadSettings,
+    query: &str,
+    output: &ExportOutputArgs,
+) {
+    let path = settings
+        .get_string("beets_library")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| home_path(".config/beets/library.db"));
+    let items = open_beets_library(&path).query(query).unwrap_or_else(|e| {
+        error!("Could not query the beets library: {}", e);
+        exit(1)
+    });
+    info!("Found {} tracks in the beets library", items.len());
+    // Files are written as they are, only a ListenBrainz playlist needs every track looked up
+    let entries: Vec<ExportEntry> = if output.format == ExportFormat::Playlist {
+        let song_data: Vec<(PathBuf, AudioIDData)> = items
+            .into_iter()
+            .map(|i| (i.path.clone(), i.audio_id_data()))
+            .collect();
+        resolve_all_songs_for_mbids(client, None, song_data, args.concurrency, args.dedupe)
+            .await
+            .into_iter()
+            .map(|s| ExportEntry {
+                mbid: Some(s.mbid),
+                file: Some(s.file_path),
+            })
+            .collect()
+    } else {
+        items
+            .into_iter()
+            .map(|i| ExportEntry {
+                mbid: i.recording_mbid,
+                file: Some(i.path),
+            })
+            .collect()
+    };
+    write_export(client, args, upload, &entries, output, query).await;
+}
+
+pub async fn write_export(
+    client: &ListenbrainzClient,
+    args: &Args,
+    upload: &UploadSettings,
+    entries: &[ExportEntry],
+    output: &ExportOutputArgs,
+    default_name: &str,
+) {
+    if entries.is_empty() {
+        info!("No recordings to export");
+        return;
+    }
+    let name = output
+        .name
+        .clone()
+        .unwrap_or_else(|| default_name.to_string());
+    let contents = match output.format {
+        ExportFormat::M3u => to_m3u(entries),
+        ExportFormat::Jspf => serde_json::to_string_pretty(&to_jspf(&name, entries))
+            .expect("JSPF is always serialisable"),
+        ExportFormat::Csv => to_csv(entries),
+        ExportFormat::Playlist => {
+            let tracks: Vec<PlaylistTrack> = entries
+                .iter()
+                .filter_map(|e| {
+                    Some(PlaylistTrack {
+                        mbid: e.mbid?,
+                        annotation: None,
+                    })
+                })
+                .collect();
+            upload_exported_playlist(client, args, upload, &tracks, &name).await;
+            return;
+        }
+    };
+    match &output.output {
+        Some(path) => {
+            if let Err(e) = fs::write(path, contents) {
+                error!("Could not write export to {:?}: {}", path, e);
+                exit(1)
+            }
+            info!("Exported {} recordings to {:?}", entries.len(), path);
+        }
+        None => print!("{}", contents),
+    }
+}
+
+/// The subcommand an export came from, which stands in for a playlist file's name
+fn export_command_name(args: &Args) -> &'static str {
+    match args.command {
+        Some(Command::TopRecordings(_)) => "top-recordings",
+        Some(Command::Recommendations(_)) => "recommendations",
+        Some(Command::Beets { .. }) => "beets",
+        _ => "feedback",
+    }
+}
+
+/// Exports are told apart by the command and the options that choose their recordings
+fn export_journal_source(args: &Args, name: &str) -> String {
+    let options = match &args.command {
+        Some(Command::TopRecordings(top)) => format!(
+            "range={} user={}",
+            top.range.api_name(),
+            top.user.as_deref().unwrap_or_default()
+        ),
+        Some(Command::Recommendations(recommendations)) => format!(
+            "user={}",
+            recommendations.user.as_deref().unwrap_or_default()
+        ),
+        Some(Command::Beets { query, .. }) => format!("query={}", query.join(" ")),
+        Some(Command::Feedback(FeedbackCommand::Export(export))) => {
+            format!("score={}", export.score as i8)
+        }
+        _ => String::new(),
+    };
+    format!("{} {} name={}", export_command_name(args), options, name)
+}
+
+/// Uploads recordings that didn't come from a playlist file, with the same options as uploads
+pub async fn upload_exported_playlist(
+    client: &ListenbrainzClient,
+    args: &Args,
+    upload: &UploadSettings,
+    tracks: &[PlaylistTrack],
+    name: &str,
+) {
+    let user_name = resolve_user_name(client).await;
+    let metadata = PlaylistMetadata {
+        title: Some(name.to_string()),
+        annotation: args.description.clone(),
+        collaborators: args.collaborators.clone(),
+        copied_from: args.copied_from,
+    };
+    let journal_source = export_journal_source(args, name);
+    let journal_path = args
+        .journal
+        .clone()
+        .unwrap_or_else(|| Journal::default_path(&journal_source));
+    let mut journal = match Journal::open(journal_path, &journal_source, name, args.resume) {
+        Ok(j) => j,
+        Err(e) => {
+            error!("Could not open journal: {}", e);
+            exit(1)
+        }
+    };
+    let tracks = match journal.tracks.clone() {
+        Some(journal_tracks) => {
+            info!(
+                "Using the {} recordings exported in a previous run",
+                journal_tracks.len()
+            );
+            journal_tracks
+        }
+        None => {
+            journal.tracks = Some(tracks.to_vec());
+            journal.checkpoint();
+            tracks.to_vec()
+        }
+    };
+    if let Some(playlist_id) = journal.playlist_id {
+        info!("Resuming upload to playlist with ID {}", playlist_id);
+        if let Err(e) = add_tracks_with_progress(
+            client,
+            &playlist_id,
+            &tracks,
+            upload.chunk_size,
+            &mut journal,
+        )
+        .await
+        {
+            error!("Could not insert remaining items into playlist: {}", e);
+            exit(1)
+        }
+    } else if let Err(e) = upload_playlist(
+        client,
+        args,
+        upload,
+        &metadata,
+        &UploadTarget {
+            user_name: &user_name,
+            source: export_command_name(args),
+        },
+        &tracks,
+        &mut journal,
+    )
+    .await
+    {
+        error!("{}", e);
+        exit(1)
+    }
+    if let Err(e) = journal.finish() {
+        warn!("Could not remove finished journal: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    fn test_entries() -> Vec<ExportEntry> {
+        vec![
+            ExportEntry {
+                mbid: Some(Uuid::from_str("36855a5c-abcb-4740-9154-361af8c11ee1").unwrap()),
+                file: Some(PathBuf::from(
+                    "/music/Ed Sheeran/Divide, Deluxe/Perfect.flac",
+                )),
+            },
+            ExportEntry {
+                mbid: Some(Uuid::from_str("00066722-b23a-48e5-82e4-0470c82a2705").unwrap()),
+                file: None,
+            },
+            ExportEntry {
+                mbid: None,
+                file: Some(PathBuf::from("/music/Unmatched.flac")),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_export_m3u_only_has_local_files() {
+        assert_eq!(
+            to_m3u(&test_entries()),
+            "#EXTM3U\n/music/Ed Sheeran/Divide, Deluxe/Perfect.flac\n/music/Unmatched.flac\n"
+        );
+    }
+
+    #[test]
+    fn test_export_jspf() {
+        let jspf = to_jspf("Loved tracks", &test_entries());
+        assert_eq!(jspf["playlist"]["title"], "Loved tracks");
+        let tracks = jspf["playlist"]["track"].as_array().unwrap();
+        assert_eq!(tracks.len(), 3);
+        assert_eq!(
+            tracks[0]["location"][0],
+            "file:///music/Ed%20Sheeran/Divide,%20Deluxe/Perfect.flac"
+        );
+        assert!(tracks[1].get("location").is_none());
+        assert!(tracks[2].get("identifier").is_none());
+    }
+
+    #[test]
+    fn test_export_csv_quotes_fields() {
+        assert_eq!(
+            to_csv(&test_entries()),
+            "recording_mbid,file\n\
+            36855a5c-abcb-4740-9154-361af8c11ee1,\"/music/Ed Sheeran/Divide, Deluxe/Perfect.flac\"\n\
+            00066722-b23a-48e5-82e4-0470c82a2705,\n\
+            ,/music/Unmatched.flac\n"
+        );
+    }
+
+    #[test]
+    fn test_export_journal_source() {
+        use clap::Parser;
+        let parse = |arguments: &[&str]| {
+            Args::try_parse_from(
+                ["listenbrainz-playlist-uploader"]
+                    .iter()
+                    .chain(arguments)
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap()
+        };
+        let args = parse(&["top-recordings", "--range", "this-month", "--count", "50"]);
+        assert_eq!(
+            export_journal_source(&args, "Top"),
+            "top-recordings range=this_month user= name=Top"
+        );
+        let args = parse(&["beets", "genre:jazz", "year:2020..", "--format", "playlist"]);
+        assert_eq!(
+            export_journal_source(&args, "Jazz"),
+            "beets query=genre:jazz year:2020.. name=Jazz"
+        );
+        let args = parse(&["feedback", "export", "--score", "hate"]);
+        assert_eq!(
+            export_journal_source(&args, "Hated tracks"),
+            "feedback score=-1 name=Hated tracks"
+        );
+    }
+}
@@ -1,28 +1,37 @@
 use crate::listenbrainz_client::ListenbrainzClient;
-use crate::paginator::ListenbrainzPaginator;
-use crate::Feedback;
+use crate::paginator::{fetch_all_pages, Page};
+use crate::upload::{load_and_resolve_songs, load_playlist_tracks, UploadContext};
+use crate::{make_progress_bar, resolve_user_name, Args, DedupePolicy, Feedback, FeedbackConflict};
 use anyhow::Result;
+use config::Config;
+use futures::stream::{self, StreamExt};
+use inquire::Confirm;
+use log::{error, info, warn};
 use serde::Deserialize;
 use serde_json::json;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::process::exit;
 use std::str::FromStr;
-use url::Url;
 use uuid::Uuid;
 
 #[derive(Deserialize)]
 struct FeedbackResponse {
     // It's possible that there are recordings with feedback that have no MBID
     recording_mbid: Option<String>,
+    score: i8,
 }
 
 #[derive(Deserialize)]
 struct FeedbackResponseWrapper {
     feedback: Vec<FeedbackResponse>,
     count: usize,
+    total_count: Option<usize>,
 }
 
 pub async fn give_song_feedback_for_mbid(
-    listenbrainz_client: &mut ListenbrainzClient,
+    listenbrainz_client: &ListenbrainzClient,
     mbid: &Uuid,
     feedback: Feedback,
 ) -> Result<()> {
@@ -41,39 +50,246 @@ pub async fn give_song_feedback_for_mbid(
     }
 }
 
-pub async fn get_existing_feedback(
-    listenbrainz_client: &mut ListenbrainzClient,
+/// Sends feedback `concurrency` requests at a time, calling `on_sent` for each that succeeds, and
+/// returns how many failed
+pub async fn give_feedback_concurrently(
+    listenbrainz_client: &ListenbrainzClient,
+    changes: Vec<(Uuid, Feedback)>,
+    concurrency: NonZeroUsize,
+    mut on_sent: impl FnMut(Uuid),
+) -> usize {
+    let progress_bar = make_progress_bar(changes.len());
+    let mut results = stream::iter(changes)
+        .map(|(mbid, feedback)| async move {
+            let out = give_song_feedback_for_mbid(listenbrainz_client, &mbid, feedback).await;
+            (mbid, out)
+        })
+        .buffer_unordered(concurrency.get());
+
+    let mut failed = 0;
+    while let Some((mbid, result)) = results.next().await {
+        progress_bar.inc(1);
+        match result {
+            Ok(()) => on_sent(mbid),
+            Err(e) => {
+                error!("Could not give feedback on {}: {}", mbid, e);
+                failed += 1;
+            }
+        }
+    }
+    progress_bar.finish();
+    failed
+}
+
+/// Gets every recording the user has given feedback on, whatever the score
+pub async fn get_all_feedback(
+    listenbrainz_client: &ListenbrainzClient,
     user_name: &str,
-    feedback: Feedback,
-) -> Result<HashSet<Uuid>> {
-    let mut all_feedback = HashSet::new();
-    for url in ListenbrainzPaginator::new(
+) -> Result<HashMap<Uuid, Feedback>> {
+    let all_feedback = fetch_all_pages(
+        listenbrainz_client,
         &format!("https://api.listenbrainz.org/1/feedback/user/{user_name}/get-feedback"),
-        0,
         1000,
-    ) {
-        let real_url =
-            Url::parse_with_params(url.as_ref(), [("score", (feedback as i8).to_string())])
-                .expect("Could not construct url");
-        let response = listenbrainz_client
-            .take_request_builder(listenbrainz_client.request_client.get(real_url))
-            .await?;
-        let response_text = response.text().await?;
-        let feedback_response: FeedbackResponseWrapper =
-            serde_json::from_str(response_text.as_str())?;
-        if feedback_response.count == 0 {
-            break;
+        parse_feedback_page,
+    )
+    .await?;
+    Ok(all_feedback.into_iter().collect())
+}
+
+fn parse_feedback_page(json: &str) -> Result<Page<(Uuid, Feedback)>> {
+    let feedback_response: FeedbackResponseWrapper = serde_json::from_str(json)?;
+    let items = feedback_response
+        .feedback
+        .into_iter()
+        .filter_map(|f| {
+            let mbid = Uuid::from_str(f.recording_mbid?.as_str()).ok()?;
+            let feedback = match f.score {
+                1 => Feedback::Love,
+                -1 => Feedback::Hate,
+                _ => Feedback::Neutral,
+            };
+            Some((mbid, feedback))
+        })
+        .collect();
+    Ok(Page {
+        items,
+        count: feedback_response.count,
+        total_count: feedback_response.total_count,
+    })
+}
+
+pub async fn sync_feedback(
+    client: &ListenbrainzClient,
+    settings: &Config,
+    args: &Args,
+    file: &PathBuf,
+    feedback: Feedback,
+    dry_run: bool,
+    max_removals: usize,
+) {
+    if feedback == Feedback::Neutral {
+        error!("Only love or hate feedback can be synced");
+        exit(1)
+    }
+    if !file.exists() {
+        error!("Given playlist file doesn't exist");
+        exit(1);
+    }
+    let user_name = resolve_user_name(client).await;
+    let all_feedback = match get_all_feedback(client, &user_name).await {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Could not get existing feedback: {}", e);
+            exit(1)
         }
-        all_feedback.extend(
-            feedback_response
-                .feedback
-                .into_iter()
-                .filter_map(|f| f.recording_mbid)
-                .map(|m| Uuid::from_str(m.as_str()))
-                .filter_map(|f| f.ok()),
+    };
+    let context = UploadContext::load(client, settings, args, user_name).await;
+    let tracks = load_playlist_tracks(file);
+    // An empty playlist means no song should have the feedback, within --max-removals
+    let resolved_songs = if tracks.is_empty() {
+        info!(
+            "The playlist is empty, so no recordings should have {:?} feedback",
+            feedback
+        );
+        Vec::new()
+    } else {
+        match load_and_resolve_songs(
+            client,
+            &context,
+            tracks,
+            args.concurrency,
+            DedupePolicy::Allow,
         )
+        .await
+        {
+            Ok(songs) => songs,
+            Err(e) => {
+                error!("{}", e);
+                exit(1)
+            }
+        }
+    };
+    let playlist_mbids: HashSet<Uuid> = resolved_songs.iter().map(|s| s.mbid).collect();
+    let existing_feedback: HashSet<Uuid> = all_feedback
+        .iter()
+        .filter(|(_, f)| **f == feedback)
+        .map(|(mbid, _)| *mbid)
+        .collect();
+
+    let playlist_mbid_list: Vec<Uuid> = playlist_mbids.iter().copied().collect();
+    // A dry run lists the conflicts without asking about them
+    let overwritten = resolve_feedback_conflicts(
+        &playlist_mbid_list,
+        &all_feedback,
+        feedback,
+        args.feedback_conflict,
+        args.no_confirm || dry_run,
+    );
+    let to_add: Vec<Uuid> = playlist_mbids
+        .difference(&existing_feedback)
+        .filter(|m| {
+            !is_feedback_conflict(current_feedback(m, &all_feedback), feedback)
+                || overwritten.contains(*m)
+        })
+        .copied()
+        .collect();
+    let to_remove: Vec<Uuid> = existing_feedback
+        .difference(&playlist_mbids)
+        .copied()
+        .collect();
+    info!(
+        "{} recordings need {:?} feedback, and {} are no longer in the playlist",
+        to_add.len(),
+        feedback,
+        to_remove.len()
+    );
+    for mbid in &to_remove {
+        info!(
+            "  reset to neutral: https://musicbrainz.org/recording/{}",
+            mbid
+        );
+    }
+    if to_remove.len() > max_removals {
+        error!(
+            "Refusing to reset {} recordings when --max-removals is {}",
+            to_remove.len(),
+            max_removals
+        );
+        error!("Check that the songs in the playlist were all resolved, then raise the limit");
+        if !dry_run {
+            exit(1)
+        }
+    }
+    if dry_run {
+        info!("Dry run, no feedback was changed");
+        return;
+    }
+
+    let changes: Vec<(Uuid, Feedback)> = to_add
+        .into_iter()
+        .map(|m| (m, feedback))
+        .chain(to_remove.into_iter().map(|m| (m, Feedback::Neutral)))
+        .collect();
+    let failed = give_feedback_concurrently(client, changes, args.concurrency, |_| {}).await;
+    if failed > 0 {
+        error!("Could not change the feedback of {} recordings", failed);
+        exit(1)
+    }
+}
+
+pub fn current_feedback(mbid: &Uuid, all_feedback: &HashMap<Uuid, Feedback>) -> Feedback {
+    all_feedback.get(mbid).copied().unwrap_or(Feedback::Neutral)
+}
+
+/// Whether giving feedback would replace the opposite feedback, rather than adding or clearing it
+pub fn is_feedback_conflict(current: Feedback, wanted: Feedback) -> bool {
+    current != wanted && current != Feedback::Neutral && wanted != Feedback::Neutral
+}
+
+/// Picks which of the songs that already have the opposite feedback should be overwritten
+pub fn resolve_feedback_conflicts(
+    musicbrainz_ids: &[Uuid],
+    all_feedback: &HashMap<Uuid, Feedback>,
+    wanted: Feedback,
+    policy: FeedbackConflict,
+    no_confirm: bool,
+) -> HashSet<Uuid> {
+    let conflicts: HashSet<Uuid> = musicbrainz_ids
+        .iter()
+        .filter(|m| is_feedback_conflict(current_feedback(m, all_feedback), wanted))
+        .copied()
+        .collect();
+    if conflicts.is_empty() {
+        return conflicts;
+    }
+    warn!(
+        "{} songs already have the opposite feedback to {:?}:",
+        conflicts.len(),
+        wanted
+    );
+    for mbid in &conflicts {
+        warn!(
+            "  https://musicbrainz.org/recording/{} ({:?})",
+            mbid,
+            current_feedback(mbid, all_feedback)
+        );
+    }
+    let overwrite = match policy {
+        FeedbackConflict::Overwrite => true,
+        FeedbackConflict::Skip => false,
+        FeedbackConflict::Prompt if no_confirm => false,
+        FeedbackConflict::Prompt => Confirm::new("Do you want to overwrite their feedback?")
+            .with_default(false)
+            .prompt()
+            .unwrap_or(false),
+    };
+    if overwrite {
+        info!("Overwriting the feedback of these songs");
+        conflicts
+    } else {
+        info!("Leaving the feedback of these songs alone");
+        HashSet::new()
     }
-    Ok(all_feedback)
 }
 
 #[cfg(test)]
@@ -82,11 +298,33 @@ mod test {
 
     #[test]
     fn test_get_existing_feedback() {
-        let mut test_client = ListenbrainzClient::new("".to_string());
-        let result = get_existing_feedback(&mut test_client, "Serene-Arc", Feedback::Love);
+        let test_client = ListenbrainzClient::new("".to_string());
+        let result = get_all_feedback(&test_client, "Serene-Arc");
         let rt = tokio::runtime::Runtime::new().unwrap();
-        let result = rt.block_on(async { result.await }).unwrap();
+        let result = rt.block_on(result).unwrap();
         // Magic number for me specifically; I know I have more than 100 favourites
-        assert!(result.len() > 100)
+        assert!(result.values().filter(|f| **f == Feedback::Love).count() > 100)
+    }
+
+    #[test]
+    fn test_parse_feedback_page() {
+        let json = r#"{"count": 3, "offset": 0, "total_count": 3, "feedback": [
+            {"recording_mbid": "36855a5c-abcb-4740-9154-361af8c11ee1", "score": 1},
+            {"recording_mbid": "00066722-b23a-48e5-82e4-0470c82a2705", "score": -1},
+            {"recording_mbid": null, "score": 1}
+        ]}"#;
+        let page = parse_feedback_page(json).unwrap();
+        assert_eq!(page.count, 3);
+        assert_eq!(page.total_count, Some(3));
+        let result: HashMap<Uuid, Feedback> = page.items.into_iter().collect();
+        assert_eq!(result.len(), 2);
+        assert_eq!(
+            result[&Uuid::from_str("36855a5c-abcb-4740-9154-361af8c11ee1").unwrap()],
+            Feedback::Love
+        );
+        assert_eq!(
+            result[&Uuid::from_str("00066722-b23a-48e5-82e4-0470c82a2705").unwrap()],
+            Feedback::Hate
+        );
     }
 }
@@ -0,0 +1,261 @@
+use crate::acoustid_client::AcoustIdClient;
+use crate::audio_data::FingerprintData;
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use log::debug;
+use rusty_chromaprint::{Configuration, FingerprintCompressor, Fingerprinter};
+use serde::Deserialize;
+use std::fs::File;
+use std::path::Path;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use uuid::Uuid;
+
+// Same as fpcalc, AcoustID only needs the start of the track
+const MAXIMUM_FINGERPRINT_SECONDS: u64 = 120;
+const MINIMUM_MATCH_SCORE: f64 = 0.5;
+
+#[derive(Deserialize)]
+struct AcoustIdResponse {
+    status: String,
+    #[serde(default)]
+    results: Vec<AcoustIdResult>,
+    error: Option<AcoustIdError>,
+}
+
+#[derive(Deserialize)]
+struct AcoustIdResult {
+    score: f64,
+    #[serde(default)]
+    recordings: Vec<AcoustIdRecording>,
+}
+
+#[derive(Deserialize)]
+struct AcoustIdRecording {
+    id: Uuid,
+}
+
+#[derive(Deserialize)]
+struct AcoustIdError {
+    message: String,
+}
+
+pub fn calculate_fingerprint(file: &Path) -> Result<FingerprintData> {
+    let source = MediaSourceStream::new(Box::new(File::open(file)?), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = file.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        source,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    let mut format = probed.format;
+    let track = format
+        .default_track()
+        .ok_or_else(|| anyhow!("Could not find an audio track"))?;
+    let track_id = track.id;
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .ok_or_else(|| anyhow!("Could not determine sample rate"))?;
+    let channels = track
+        .codec_params
+        .channels
+        .ok_or_else(|| anyhow!("Could not determine channel count"))?
+        .count();
+    let total_frames = track.codec_params.n_frames;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let config = Configuration::preset_test2();
+    let mut printer = Fingerprinter::new(&config);
+    printer.start(sample_rate, channels as u32)?;
+
+    let maximum_frames = MAXIMUM_FINGERPRINT_SECONDS * sample_rate as u64;
+    let mut decoded_frames: u64 = 0;
+    let mut sample_buffer: Option<SampleBuffer<i16>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(p) => p,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(d) => d,
+            Err(SymphoniaError::DecodeError(e)) => {
+                debug!("Skipping undecodable packet in {:?}: {}", file, e);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let frames_in_packet = decoded.frames() as u64;
+        if decoded_frames < maximum_frames {
+            let buffer = sample_buffer.get_or_insert_with(|| {
+                SampleBuffer::new(decoded.capacity() as u64, *decoded.spec())
+            });
+            buffer.copy_interleaved_ref(decoded);
+            printer.consume(buffer.samples());
+        } else if total_frames.is_some() {
+            // Only keep decoding past the limit if we need to count the duration ourselves
+            break;
+        }
+        decoded_frames += frames_in_packet;
+    }
+    printer.finish();
+
+    if printer.fingerprint().is_empty() {
+        return Err(anyhow!("Audio was too short to fingerprint"));
+    }
+    let compressed = FingerprintCompressor::from(&config).compress(printer.fingerprint());
+    Ok(FingerprintData {
+        fingerprint: URL_SAFE_NO_PAD.encode(compressed),
+        duration: (total_frames.unwrap_or(decoded_frames) / sample_rate as u64) as u32,
+    })
+}
+
+pub async fn get_musicbrainz_id_for_fingerprint(
+    acoustid_client: &AcoustIdClient,
+    fingerprint_data: FingerprintData,
+) -> Result<Uuid> {
+    let parameters = [
+        ("client", acoustid_client.api_key.clone()),
+        ("meta", "recordings".to_string()),
+        ("duration", fingerprint_data.duration.to_string()),
+        ("fingerprint", fingerprint_data.fingerprint),
+    ];
+    let response = acoustid_client
+        .take_request_builder(
+            acoustid_client
+                .request_client
+                .post(&acoustid_client.lookup_url)
+                .form(&parameters),
+        )
+        .await?
+        .json::<AcoustIdResponse>()
+        .await?;
+
+    if response.status != "ok" {
+        return Err(anyhow!(
+            "AcoustID lookup failed: {}",
+            response.error.map(|e| e.message).unwrap_or(response.status)
+        ));
+    }
+
+    response
+        .results
+        .into_iter()
+        .filter(|r| r.score >= MINIMUM_MATCH_SCORE)
+        .max_by(|a, b| a.score.total_cmp(&b.score))
+        .and_then(|r| r.recordings.into_iter().next())
+        .map(|r| r.id)
+        .ok_or_else(|| anyhow!("No recording matched the fingerprint"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::f32::consts::PI;
+    use std::str::FromStr;
+
+    fn make_test_wav(name: &str, seconds: u32) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("lpu-{}-{}.wav", std::process::id(), name));
+        let sample_rate: u32 = 11025;
+        let samples: Vec<u8> = (0..sample_rate * seconds)
+            .map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                // Change the pitch every half second so there is something to fingerprint
+                let frequency = 220.0 * (1 + (i / (sample_rate / 2)) % 4) as f32;
+                ((t * frequency * 2.0 * PI).sin() * 16000.0) as i16
+            })
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36u32 + samples.len() as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&sample_rate.to_le_bytes());
+        wav.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&samples);
+        std::fs::write(&path, wav).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_calculate_fingerprint() {
+        let path = make_test_wav("fingerprint", 10);
+        let result = calculate_fingerprint(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(result.duration, 10);
+        assert!(!result.fingerprint.is_empty());
+    }
+
+    #[test]
+    fn test_get_musicbrainz_id_for_fingerprint() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut server = rt.block_on(mockito::Server::new_async());
+        let mock = server
+            .mock("POST", "/v2/lookup")
+            .match_body(mockito::Matcher::UrlEncoded(
+                "fingerprint".to_string(),
+                "AQAAAA".to_string(),
+            ))
+            .with_body(
+                r#"{"status": "ok", "results": [
+                    {"id": "a", "score": 0.2, "recordings": [{"id": "00066722-b23a-48e5-82e4-0470c82a2705"}]},
+                    {"id": "b", "score": 0.9, "recordings": [{"id": "36855a5c-abcb-4740-9154-361af8c11ee1"}]}
+                ]}"#,
+            )
+            .create();
+        let client = AcoustIdClient::new("key".to_string(), format!("{}/v2/lookup", server.url()));
+        let data = FingerprintData {
+            fingerprint: "AQAAAA".to_string(),
+            duration: 100,
+        };
+        let result = rt
+            .block_on(get_musicbrainz_id_for_fingerprint(&client, data))
+            .unwrap();
+        mock.assert();
+        assert_eq!(
+            result,
+            Uuid::from_str("36855a5c-abcb-4740-9154-361af8c11ee1").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_get_musicbrainz_id_for_fingerprint_no_match() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut server = rt.block_on(mockito::Server::new_async());
+        server
+            .mock("POST", "/v2/lookup")
+            .with_body(r#"{"status": "ok", "results": []}"#)
+            .create();
+        let client = AcoustIdClient::new("key".to_string(), format!("{}/v2/lookup", server.url()));
+        let data = FingerprintData {
+            fingerprint: "AQAAAA".to_string(),
+            duration: 100,
+        };
+        let result = rt.block_on(get_musicbrainz_id_for_fingerprint(&client, data));
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use crate::audio_data::ResolvedSong;
+use crate::playlist::PlaylistTrack;
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Records the steps of an upload that have been confirmed, so that a failed run can be resumed
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Journal {
+    #[serde(skip)]
+    path: PathBuf,
+    // What is uploaded: a playlist file, or the command and options an export came from
+    pub source: String,
+    pub playlist_name: String,
+    pub resolved_songs: Option<Vec<ResolvedSong>>,
+    // Exports keep their tracks, as fetching them again may give a different list
+    pub tracks: Option<Vec<PlaylistTrack>>,
+    pub playlist_id: Option<Uuid>,
+    pub tracks_added: usize,
+    pub feedback_sent: HashSet<Uuid>,
+    // A library upload records each of its playlists that finished, so a resumed run skips them
+    #[serde(default)]
+    pub playlists_finished: HashSet<String>,
+}
+
+impl Journal {
+    /// Journals are kept in the cache directory rather than next to playlists, named after what is
+    /// being uploaded so that a resumed run finds its own
+    pub fn default_path(source: &str) -> PathBuf {
+        let cache_directory = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".cache")))
+            .unwrap_or_else(std::env::temp_dir);
+        let name = Uuid::new_v5(&Uuid::NAMESPACE_URL, source.as_bytes());
+        cache_directory
+            .join("listenbrainz-playlist-uploader")
+            .join(format!("{name}.journal.json"))
+    }
+
+    pub fn open(path: PathBuf, source: &str, playlist_name: &str, resume: bool) -> Result<Self> {
+        if path.exists() {
+            if resume {
+                let mut journal: Journal = serde_json::from_str(&fs::read_to_string(&path)?)?;
+                if journal.source != source || journal.playlist_name != playlist_name {
+                    return Err(anyhow!(
+                        "Journal at {:?} is for uploading {} as '{}'",
+                        path,
+                        journal.source,
+                        journal.playlist_name
+                    ));
+                }
+                journal.path = path;
+                return Ok(journal);
+            }
+            warn!("Discarding the journal of a previous run at {:?}", path);
+        } else if resume {
+            warn!(
+                "No journal found at {:?}, starting from the beginning",
+                path
+            );
+        }
+        let journal = Journal {
+            path,
+            source: source.to_string(),
+            playlist_name: playlist_name.to_string(),
+            ..Default::default()
+        };
+        journal.save()?;
+        Ok(journal)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        // Write then rename so a crash can't leave a half-written journal
+        if let Some(directory) = self.path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        let temporary_path = self.path.with_extension("tmp");
+        fs::write(&temporary_path, serde_json::to_string(self)?)?;
+        fs::rename(temporary_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Saves the journal, only warning on failure since the run itself can carry on
+    pub fn checkpoint(&self) {
+        if let Err(e) = self.save() {
+            warn!("Could not save journal to {:?}: {}", self.path, e);
+        }
+    }
+
+    pub fn finish(self) -> Result<()> {
+        debug!("Removing finished journal at {:?}", self.path);
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audio_data::AudioIDData;
+    use std::str::FromStr;
+
+    fn temporary_journal_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("lpu-{}-{}.journal.json", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_journal_resume() {
+        let path = temporary_journal_path("resume");
+        let source = "./tests/test_playlist_1.m3u";
+        let mbid = Uuid::from_str("36855a5c-abcb-4740-9154-361af8c11ee1").unwrap();
+
+        let mut journal = Journal::open(path.clone(), source, "Example", false).unwrap();
+        journal.resolved_songs = Some(vec![ResolvedSong {
+            file_path: PathBuf::from("/music/song.flac"),
+            mbid,
+            source: AudioIDData::Mbid(mbid),
+        }]);
+        journal.playlist_id = Some(mbid);
+        journal.tracks_added = 100;
+        journal.save().unwrap();
+
+        let resumed = Journal::open(path.clone(), source, "Example", true).unwrap();
+        assert_eq!(resumed.resolved_songs.unwrap()[0].mbid, mbid);
+        assert_eq!(resumed.playlist_id, Some(mbid));
+        assert_eq!(resumed.tracks_added, 100);
+
+        let fresh = Journal::open(path.clone(), source, "Example", false).unwrap();
+        assert!(fresh.resolved_songs.is_none());
+        fresh.finish().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_journal_default_path_depends_on_upload() {
+        let path = Journal::default_path("/music/road_trip.m3u");
+        assert_eq!(path, Journal::default_path("/music/road_trip.m3u"));
+        assert_ne!(path, Journal::default_path("/music/other.m3u"));
+        assert!(path
+            .parent()
+            .unwrap()
+            .ends_with("listenbrainz-playlist-uploader"));
+    }
+
+    #[test]
+    fn test_journal_resume_different_playlist() {
+        let path = temporary_journal_path("different");
+        let source = "./tests/test_playlist_1.m3u";
+        Journal::open(path.clone(), source, "Example", false).unwrap();
+        let result = Journal::open(path.clone(), source, "Other", true);
+        assert!(result.is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_journal_resume_finished_playlists() {
+        let path = temporary_journal_path("library");
+        let source = "library ./tests/rhythmdb/playlists.xml";
+        let mut journal = Journal::open(path.clone(), source, "", false).unwrap();
+        journal.playlists_finished.insert("Road trip".to_string());
+        journal.save().unwrap();
+
+        let resumed = Journal::open(path.clone(), source, "", true).unwrap();
+        assert!(resumed.playlists_finished.contains("Road trip"));
+        assert!(!resumed.playlists_finished.contains("Party"));
+        resumed.finish().unwrap();
+    }
+}
@@ -0,0 +1,383 @@
+use crate::Args;
+use anyhow::Result;
+use config::Config;
+use lofty::file::{AudioFile, TaggedFileExt};
+use lofty::tag::{Accessor, ItemKey};
+use log::{debug, error, info, warn};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
+use uuid::Uuid;
+use walkdir::WalkDir;
+
+const AUDIO_EXTENSIONS: [&str; 10] = [
+    "mp3", "flac", "ogg", "oga", "opus", "m4a", "mp4", "wav", "aiff", "wv",
+];
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS files (
+    path TEXT PRIMARY KEY,
+    modified INTEGER NOT NULL,
+    recording_mbid TEXT,
+    track_mbid TEXT,
+    release_mbid TEXT,
+    artist TEXT,
+    title TEXT,
+    album TEXT,
+    duration_ms INTEGER
+);
+CREATE INDEX IF NOT EXISTS files_recording_mbid ON files (recording_mbid);
+";
+
+/// What was read from the tags of a file in the library when it was last scanned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexedFile {
+    pub path: PathBuf,
+    pub modified: i64,
+    pub recording_mbid: Option<Uuid>,
+    pub track_mbid: Option<Uuid>,
+    pub release_mbid: Option<Uuid>,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub duration_ms: Option<i64>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ScanSummary {
+    pub read: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+}
+
+/// An SQLite index of the audio files in music directories, so that files can be found by MBID
+pub struct LibraryIndex {
+    connection: Connection,
+}
+
+impl LibraryIndex {
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(LibraryIndex { connection })
+    }
+
+    /// Walks the directories, only reading the tags of files that changed since the last scan, and
+    /// forgets files under them that no longer exist
+    pub fn scan(&mut self, directories: &[PathBuf]) -> Result<ScanSummary> {
+        let known = self.modified_times()?;
+        let mut summary = ScanSummary::default();
+        let transaction = self.connection.transaction()?;
+        // A directory or file that can't be read is skipped rather than losing the whole scan
+        for directory in directories {
+            let directory = match directory.canonicalize() {
+                Ok(d) => d,
+                Err(e) => {
+                    warn!("Skipping {:?}, as it can't be read: {}", directory, e);
+                    continue;
+                }
+            };
+            debug!("Scanning {:?} for audio files", directory);
+            let mut seen = HashSet::new();
+            for path in audio_files_under(&directory) {
+                let Some(path_text) = path.to_str().map(str::to_string) else {
+                    warn!("Skipping {:?}, as its path isn't valid UTF-8", path);
+                    continue;
+                };
+                seen.insert(path_text.clone());
+                let modified = match modified_time(&path) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        warn!("Skipping {:?}, as it can't be read: {}", path, e);
+                        continue;
+                    }
+                };
+                if known.get(&path_text) == Some(&modified) {
+                    summary.unchanged += 1;
+                    continue;
+                }
+                let file = read_file_details(&path, modified);
+                transaction.execute(
+                    "INSERT OR REPLACE INTO files VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    params![
+                        path_text,
+                        file.modified,
+                        file.recording_mbid.map(|m| m.to_string()),
+                        file.track_mbid.map(|m| m.to_string()),
+                        file.release_mbid.map(|m| m.to_string()),
+                        file.artist,
+                        file.title,
+                        file.album,
+                        file.duration_ms,
+                    ],
+                )?;
+                summary.read += 1;
+            }
+            for path in known.keys() {
+                if Path::new(path).starts_with(&directory) && !seen.contains(path) {
+                    transaction.execute("DELETE FROM files WHERE path = ?1", params![path])?;
+                    summary.removed += 1;
+                }
+            }
+        }
+        transaction.commit()?;
+        info!(
+            "Read {} files, {} were unchanged and {} were removed",
+            summary.read, summary.unchanged, summary.removed
+        );
+        Ok(summary)
+    }
+
+    /// Gets what's known about a file, as long as it hasn't changed since it was scanned
+    pub fn get_if_unchanged(&self, path: &Path) -> Option<IndexedFile> {
+        let path = path.canonicalize().ok()?;
+        let indexed = self
+            .connection
+            .query_row(
+                "SELECT * FROM files WHERE path = ?1",
+                params![path.to_str()?],
+                indexed_file_from_row,
+            )
+            .optional()
+            .unwrap_or_else(|e| {
+                warn!("Could not read the library index: {}", e);
+                None
+            })?;
+        (modified_time(&path).ok()? == indexed.modified).then_some(indexed)
+    }
+
+    /// Gets a file for each recording MBID in the library
+    pub fn recording_files(&self) -> Result<HashMap<Uuid, PathBuf>> {
+        let mut statement = self.connection.prepare(
+            "SELECT recording_mbid, path FROM files WHERE recording_mbid IS NOT NULL ORDER BY path",
+        )?;
+        let mut files = HashMap::new();
+        for row in statement.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get(1)?)))? {
+            let (mbid, path): (String, String) = row?;
+            if let Ok(mbid) = Uuid::from_str(&mbid) {
+                files.entry(mbid).or_insert_with(|| PathBuf::from(path));
+            }
+        }
+        Ok(files)
+    }
+
+    /// Gets a file for each artist and title among files without a recording MBID in their tags,
+    /// keyed by `name_key`
+    pub fn untagged_files_by_name(&self) -> Result<HashMap<(String, String), PathBuf>> {
+        let mut statement = self.connection.prepare(
+            "SELECT artist, title, path FROM files WHERE recording_mbid IS NULL
+            AND artist IS NOT NULL AND title IS NOT NULL ORDER BY path",
+        )?;
+        let mut files = HashMap::new();
+        for row in statement.query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))? {
+            let (artist, title, path): (String, String, String) = row?;
+            files
+                .entry(name_key(&artist, &title))
+                .or_insert_with(|| PathBuf::from(path));
+        }
+        Ok(files)
+    }
+
+    fn modified_times(&self) -> Result<HashMap<String, i64>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT path, modified FROM files")?;
+        let times = statement
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(times)
+    }
+}
+
+/// Artist and title compared without case or surrounding whitespace
+pub fn name_key(artist: &str, title: &str) -> (String, String) {
+    (artist.trim().to_lowercase(), title.trim().to_lowercase())
+}
+
+fn indexed_file_from_row(row: &Row) -> rusqlite::Result<IndexedFile> {
+    let mbid = |index: usize| -> rusqlite::Result<Option<Uuid>> {
+        Ok(row
+            .get::<_, Option<String>>(index)?
+            .and_then(|m| Uuid::from_str(&m).ok()))
+    };
+    Ok(IndexedFile {
+        path: PathBuf::from(row.get::<_, String>(0)?),
+        modified: row.get(1)?,
+        recording_mbid: mbid(2)?,
+        track_mbid: mbid(3)?,
+        release_mbid: mbid(4)?,
+        artist: row.get(5)?,
+        title: row.get(6)?,
+        album: row.get(7)?,
+        duration_ms: row.get(8)?,
+    })
+}
+
+fn audio_files_under(directory: &Path) -> impl Iterator<Item = PathBuf> {
+    WalkDir::new(directory)
+        .follow_links(true)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .map(|e| e.into_path())
+        .filter(|path| {
+            path.extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| AUDIO_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+        })
+}
+
+fn modified_time(path: &Path) -> Result<i64> {
+    let modified = fs::metadata(path)?.modified()?;
+    Ok(modified.duration_since(UNIX_EPOCH)?.as_millis() as i64)
+}
+
+/// Reads the details of a file, leaving out anything that can't be read so that files without
+/// tags are still remembered and not read again
+fn read_file_details(path: &Path, modified: i64) -> IndexedFile {
+    let mut file = IndexedFile {
+        path: path.to_path_buf(),
+        modified,
+        recording_mbid: None,
+        track_mbid: None,
+        release_mbid: None,
+        artist: None,
+        title: None,
+        album: None,
+        duration_ms: None,
+    };
+    let tagged_file = match lofty::read_from_path(path) {
+        Ok(f) => f,
+        Err(e) => {
+            debug!("Could not read {:?}: {}", path, e);
+            return file;
+        }
+    };
+    file.duration_ms = Some(tagged_file.properties().duration().as_millis() as i64);
+    if let Some(tag) = tagged_file.primary_tag().or(tagged_file.first_tag()) {
+        let mbid = |key: ItemKey| {
+            tag.get_string(&key)
+                .and_then(|m| Uuid::from_str(m.trim()).ok())
+        };
+        file.recording_mbid = mbid(ItemKey::MusicBrainzRecordingId);
+        file.track_mbid = mbid(ItemKey::MusicBrainzTrackId);
+        file.release_mbid = mbid(ItemKey::MusicBrainzReleaseId);
+        file.artist = tag.artist().map(|a| a.to_string());
+        file.title = tag.title().map(|t| t.to_string());
+        file.album = tag.album().map(|a| a.to_string());
+    }
+    file
+}
+
+pub fn scan_library(settings: &Config, args: &Args, directories: &[PathBuf]) {
+    let directories = load_library_paths(settings, directories);
+    if directories.is_empty() {
+        error!("No directories given to scan, and no library_paths in the configuration");
+        exit(1)
+    }
+    if let Err(e) = open_library_index(settings, args).scan(&directories) {
+        error!("Could not scan the music library: {}", e);
+        exit(1)
+    }
+}
+
+pub fn load_library_paths(settings: &Config, given: &[PathBuf]) -> Vec<PathBuf> {
+    if !given.is_empty() {
+        return given.to_vec();
+    }
+    settings
+        .get::<Vec<String>>("library_paths")
+        .unwrap_or_default()
+        .into_iter()
+        .map(PathBuf::from)
+        .collect()
+}
+
+pub fn library_index_path(settings: &Config, args: &Args) -> PathBuf {
+    settings
+        .get_string("library_index")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| args.config.with_file_name("library.sqlite"))
+}
+
+pub fn open_library_index(settings: &Config, args: &Args) -> LibraryIndex {
+    let path = library_index_path(settings, args);
+    debug!("Opening library index at {:?}", path);
+    LibraryIndex::open(&path).unwrap_or_else(|e| {
+        error!("Could not open library index at {:?}: {}", path, e);
+        exit(1)
+    })
+}
+
+/// Opens the library index only if a scan has made one, as uploads work without it
+pub fn open_existing_library_index(settings: &Config, args: &Args) -> Option<LibraryIndex> {
+    library_index_path(settings, args)
+        .exists()
+        .then(|| open_library_index(settings, args))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tag_writer::test::make_test_mp3;
+    use crate::tag_writer::{write_mbids_to_file, TagWriteOptions};
+    use lofty::config::WriteOptions;
+    use lofty::tag::{Tag, TagExt, TagType};
+
+    #[test]
+    fn test_scan_library() {
+        let directory = std::env::temp_dir().join(format!("lpu-{}-library", std::process::id()));
+        fs::create_dir_all(directory.join("Artist")).unwrap();
+        let directory = directory.canonicalize().unwrap();
+        let mbid = Uuid::from_str("36855a5c-abcb-4740-9154-361af8c11ee1").unwrap();
+
+        let tagged = directory.join("Artist").join("tagged.mp3");
+        fs::rename(make_test_mp3("library-tagged"), &tagged).unwrap();
+        write_mbids_to_file(&tagged, &mbid, None, TagWriteOptions::default()).unwrap();
+        let untagged = directory.join("untagged.mp3");
+        fs::rename(make_test_mp3("library-untagged"), &untagged).unwrap();
+        let mut tag = Tag::new(TagType::Id3v2);
+        tag.set_artist("Ed Sheeran".to_string());
+        tag.set_title("Perfect".to_string());
+        tag.save_to_path(&untagged, WriteOptions::default())
+            .unwrap();
+        fs::write(directory.join("cover.jpg"), [0u8; 16]).unwrap();
+
+        let index_path = directory.join("index.sqlite");
+        let mut index = LibraryIndex::open(&index_path).unwrap();
+        let summary = index
+            .scan(&[directory.clone(), directory.join("missing")])
+            .unwrap();
+        assert_eq!(summary.read, 2);
+        let files = index.recording_files().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[&mbid], tagged);
+        let files = index.untagged_files_by_name().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[&name_key(" ed sheeran", "PERFECT")], untagged);
+        assert_eq!(
+            index.get_if_unchanged(&tagged).unwrap().recording_mbid,
+            Some(mbid)
+        );
+
+        // Only changed files are read again
+        fs::remove_file(&untagged).unwrap();
+        let summary = LibraryIndex::open(&index_path)
+            .unwrap()
+            .scan(std::slice::from_ref(&directory))
+            .unwrap();
+        assert_eq!(
+            summary,
+            ScanSummary {
+                read: 0,
+                unchanged: 1,
+                removed: 1
+            }
+        );
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
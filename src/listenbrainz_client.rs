@@ -26,10 +26,7 @@ impl ListenbrainzClient {
         }
     }
 
-    pub async fn take_request_builder(
-        &mut self,
-        request_builder: RequestBuilder,
-    ) -> Result<Response> {
+    pub async fn take_request_builder(&self, request_builder: RequestBuilder) -> Result<Response> {
         let request_builder =
             request_builder.header(AUTHORIZATION, format!("Token {}", self.user_token));
         self.rate_limiter.until_ready().await;
@@ -52,3 +49,37 @@ impl ListenbrainzClient {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future::join_all;
+    use std::time::Instant;
+
+    #[test]
+    fn test_concurrent_requests_share_client() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut server = rt.block_on(mockito::Server::new_async());
+        let mock = server
+            .mock("GET", "/")
+            .match_header("authorization", "Token abc")
+            .with_chunked_body(|w| {
+                std::thread::sleep(Duration::from_millis(500));
+                w.write_all(b"{}")
+            })
+            .expect(5)
+            .create();
+        let client = ListenbrainzClient::new("abc".to_string());
+        let start = Instant::now();
+        let results = rt.block_on(join_all((0..5).map(|_| async {
+            let response = client
+                .take_request_builder(client.request_client.get(server.url()))
+                .await?;
+            Ok::<String, anyhow::Error>(response.text().await?)
+        })));
+        // Each response takes half a second, so one after another they would take two and a half
+        assert!(start.elapsed() < Duration::from_millis(1500));
+        mock.assert();
+        assert!(results.iter().all(|r| r.is_ok()));
+    }
+}
@@ -0,0 +1,453 @@
+use crate::audio_data::{AudioFileData, AudioIDData};
+use crate::listenbrainz_client::ListenbrainzClient;
+use crate::paginator::fetch_page;
+use crate::upload::resolve_batch_lookups;
+use crate::{audio_data, make_progress_bar, resolve_user_name, Args};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, TimeZone};
+use futures::stream::{self, StreamExt};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::process::exit;
+use std::str::FromStr;
+use std::sync::Arc;
+use url::Url;
+use uuid::Uuid;
+
+const LISTENS_PER_PAGE: usize = 1000;
+pub const LISTENS_PER_SUBMISSION: usize = 100;
+
+/// A song played on a portable player, from a line of an Audioscrobbler `.scrobbler.log`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScrobblerLogEntry {
+    pub artist: String,
+    pub album: Option<String>,
+    pub title: String,
+    pub track_number: Option<u32>,
+    pub duration: Option<u32>,
+    pub listened_at: i64,
+    pub recording_mbid: Option<Uuid>,
+}
+
+#[derive(Deserialize)]
+struct ListensResponse {
+    payload: ListensPayload,
+}
+
+#[derive(Deserialize)]
+struct ListensPayload {
+    listens: Vec<ExistingListen>,
+}
+
+#[derive(Deserialize)]
+struct ExistingListen {
+    listened_at: i64,
+    track_metadata: ExistingTrackMetadata,
+}
+
+#[derive(Deserialize)]
+struct ExistingTrackMetadata {
+    artist_name: String,
+    track_name: String,
+}
+
+/// A listen as told apart from others at the same time: its time, artist and title
+pub type ListenKey = (i64, String, String);
+
+impl ScrobblerLogEntry {
+    pub fn key(&self) -> ListenKey {
+        (self.listened_at, self.artist.clone(), self.title.clone())
+    }
+}
+
+/// Reads the songs that were listened to from a scrobbler log, leaving out skipped ones
+pub fn parse_scrobbler_log(contents: &str) -> Result<Vec<ScrobblerLogEntry>> {
+    let mut local_time = false;
+    let mut entries = Vec::new();
+    for (line_number, line) in contents.lines().enumerate() {
+        if let Some(header) = line.strip_prefix('#') {
+            // Players without a clock set to UTC write local times
+            if let Some(timezone) = header.strip_prefix("TZ/") {
+                local_time = timezone.trim() != "UTC";
+            }
+            continue;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 7 {
+            return Err(anyhow!(
+                "Line {} of the scrobbler log has {} fields rather than at least 7",
+                line_number + 1,
+                fields.len()
+            ));
+        }
+        if fields[5] != "L" {
+            debug!("Skipping skipped song {} - {}", fields[0], fields[2]);
+            continue;
+        }
+        let timestamp: i64 = fields[6].parse().map_err(|e| {
+            anyhow!(
+                "Invalid timestamp on line {} of the scrobbler log: {}",
+                line_number + 1,
+                e
+            )
+        })?;
+        let listened_at = if local_time {
+            local_timestamp_to_utc(timestamp)?
+        } else {
+            timestamp
+        };
+        entries.push(ScrobblerLogEntry {
+            artist: fields[0].to_string(),
+            album: Some(fields[1].to_string()).filter(|a| !a.is_empty()),
+            title: fields[2].to_string(),
+            track_number: fields[3].parse().ok(),
+            duration: fields[4].parse().ok(),
+            listened_at,
+            recording_mbid: fields.get(7).and_then(|m| Uuid::from_str(m.trim()).ok()),
+        });
+    }
+    Ok(entries)
+}
+
+fn local_timestamp_to_utc(timestamp: i64) -> Result<i64> {
+    let naive = DateTime::from_timestamp(timestamp, 0)
+        .ok_or_else(|| anyhow!("Timestamp {} is out of range", timestamp))?
+        .naive_utc();
+    let local = Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| {
+            anyhow!(
+                "Timestamp {} doesn't exist in the local timezone",
+                timestamp
+            )
+        })?;
+    Ok(local.timestamp())
+}
+
+pub fn listen_to_json(entry: &ScrobblerLogEntry, mbid: Option<&Uuid>) -> Value {
+    let mut additional_info = json!({
+        "submission_client": env!("CARGO_PKG_NAME"),
+        "submission_client_version": env!("CARGO_PKG_VERSION"),
+    });
+    if let Some(mbid) = mbid {
+        additional_info["recording_mbid"] = json!(mbid);
+    }
+    if let Some(duration) = entry.duration {
+        additional_info["duration_ms"] = json!(u64::from(duration) * 1000);
+    }
+    if let Some(track_number) = entry.track_number {
+        additional_info["tracknumber"] = json!(track_number);
+    }
+    let mut track_metadata = json!({
+        "artist_name": entry.artist,
+        "track_name": entry.title,
+        "additional_info": additional_info,
+    });
+    if let Some(album) = &entry.album {
+        track_metadata["release_name"] = json!(album);
+    }
+    json!({"listened_at": entry.listened_at, "track_metadata": track_metadata})
+}
+
+pub async fn submit_listens(
+    listenbrainz_client: &ListenbrainzClient,
+    listens: &[Value],
+) -> Result<()> {
+    let data = json!({"listen_type": "import", "payload": listens});
+    listenbrainz_client
+        .take_request_builder(
+            listenbrainz_client
+                .request_client
+                .post("https://api.listenbrainz.org/1/submit-listens")
+                .json(&data),
+        )
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Gets the user's listens from `min_ts` up to `max_ts`, walking back from the newest
+pub async fn get_existing_listens(
+    listenbrainz_client: &ListenbrainzClient,
+    user_name: &str,
+    min_ts: i64,
+    max_ts: i64,
+) -> Result<HashSet<ListenKey>> {
+    fetch_listens(
+        listenbrainz_client,
+        &format!("https://api.listenbrainz.org/1/user/{user_name}/listens"),
+        LISTENS_PER_PAGE,
+        min_ts,
+        max_ts,
+    )
+    .await
+}
+
+async fn fetch_listens(
+    listenbrainz_client: &ListenbrainzClient,
+    listens_url: &str,
+    page_size: usize,
+    min_ts: i64,
+    max_ts: i64,
+) -> Result<HashSet<ListenKey>> {
+    let mut listens = HashSet::new();
+    // max_ts is exclusive
+    let mut before = max_ts + 1;
+    loop {
+        let url = Url::parse_with_params(
+            listens_url,
+            [
+                ("max_ts", before.to_string()),
+                ("count", page_size.to_string()),
+            ],
+        )?;
+        let response: ListensResponse =
+            serde_json::from_str(&fetch_page(listenbrainz_client, url).await?)?;
+        let page_length = response.payload.listens.len();
+        let Some(oldest) = response.payload.listens.iter().map(|l| l.listened_at).min() else {
+            break;
+        };
+        listens.extend(response.payload.listens.into_iter().map(|l| {
+            (
+                l.listened_at,
+                l.track_metadata.artist_name,
+                l.track_metadata.track_name,
+            )
+        }));
+        if oldest < min_ts || page_length < page_size {
+            break;
+        }
+        // The page may have ended part of the way through the listens at its oldest time, so
+        // those are fetched again with the next page
+        if oldest + 1 >= before {
+            warn!("Listen history didn't go back past {}, stopping", oldest);
+            break;
+        }
+        before = oldest + 1;
+    }
+    Ok(listens)
+}
+
+pub async fn import_listens(
+    client: &ListenbrainzClient,
+    args: &Args,
+    file: &PathBuf,
+    dry_run: bool,
+) {
+    let entries = match fs::read_to_string(file)
+        .map_err(anyhow::Error::from)
+        .and_then(|c| parse_scrobbler_log(&c))
+    {
+        Ok(e) => e,
+        Err(e) => {
+            error!("Could not read scrobbler log: {}", e);
+            exit(1)
+        }
+    };
+    info!("Found {} listens in the scrobbler log", entries.len());
+    let (Some(min_ts), Some(max_ts)) = (
+        entries.iter().map(|e| e.listened_at).min(),
+        entries.iter().map(|e| e.listened_at).max(),
+    ) else {
+        return;
+    };
+
+    let user_name = resolve_user_name(client).await;
+    let existing = match get_existing_listens(client, &user_name, min_ts, max_ts).await {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Could not get existing listens: {}", e);
+            exit(1)
+        }
+    };
+    let number_of_entries = entries.len();
+    let new_entries: Vec<_> = entries
+        .into_iter()
+        .filter(|e| !existing.contains(&e.key()))
+        .collect();
+    info!(
+        "{}/{} listens are already in your history",
+        number_of_entries - new_entries.len(),
+        number_of_entries
+    );
+    if new_entries.is_empty() {
+        return;
+    }
+
+    info!("Resolving listens to Musicbrainz IDs...");
+    let song_data: Vec<AudioIDData> = new_entries
+        .iter()
+        .map(|entry| match entry.recording_mbid {
+            Some(mbid) => AudioIDData::Mbid(mbid),
+            None => AudioIDData::AudioFileData(AudioFileData {
+                artist: entry.artist.clone(),
+                title: entry.title.clone(),
+                album: entry.album.clone(),
+            }),
+        })
+        .collect();
+    let batch_results = resolve_batch_lookups(client, song_data.iter(), args.concurrency).await;
+
+    let progress_bar = make_progress_bar(song_data.len());
+    // Buffered rather than unordered so that each MBID stays with its listen
+    let mbids: Vec<Option<Uuid>> = stream::iter(song_data.iter().enumerate())
+        .map(|(index, data)| {
+            let pb = Arc::clone(&progress_bar);
+            let batch_result = batch_results.get(&index).copied();
+            async move {
+                let out = match (data, batch_result) {
+                    (AudioIDData::Mbid(mbid), _) => Some(*mbid),
+                    (_, Some(mbid)) => Some(mbid),
+                    // Misses from the batch lookup get another chance, including artist aliases
+                    (AudioIDData::AudioFileData(d), None) => {
+                        audio_data::get_musicbrainz_id_for_audio_data(client, d.clone())
+                            .await
+                            .map_err(|e| debug!("Could not resolve listen: {}", e))
+                            .ok()
+                    }
+                    _ => None,
+                };
+                pb.inc(1);
+                out
+            }
+        })
+        .buffered(args.concurrency.get())
+        .collect()
+        .await;
+    progress_bar.finish();
+    let submissions: Vec<serde_json::Value> = new_entries
+        .iter()
+        .zip(&mbids)
+        .map(|(entry, mbid)| listen_to_json(entry, mbid.as_ref()))
+        .collect();
+    let unresolved = submissions
+        .iter()
+        .filter(|l| l["track_metadata"]["additional_info"]["recording_mbid"].is_null())
+        .count();
+    if unresolved > 0 {
+        warn!(
+            "{} listens could not be resolved and are sent without an MBID",
+            unresolved
+        );
+    }
+    if dry_run {
+        info!("Dry run, {} listens were not submitted", submissions.len());
+        return;
+    }
+
+    let progress_bar = make_progress_bar(submissions.len());
+    for chunk in submissions.chunks(LISTENS_PER_SUBMISSION) {
+        if let Err(e) = submit_listens(client, chunk).await {
+            error!("Could not submit listens: {}", e);
+            error!("Run again to submit the rest, listens already sent are skipped");
+            exit(1)
+        }
+        progress_bar.inc(chunk.len() as u64);
+    }
+    progress_bar.finish();
+    info!("Submitted {} listens", submissions.len());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_scrobbler_log() {
+        let log = "#AUDIOSCROBBLER/1.1\n\
+            #TZ/UTC\n\
+            #CLIENT/Rockbox sansaclipplus $Revision$\n\
+            Ed Sheeran\t÷ (Deluxe)\tPerfect\t5\t263\tL\t1700000000\t36855a5c-abcb-4740-9154-361af8c11ee1\n\
+            Ed Sheeran\t÷ (Deluxe)\tDive\t4\t238\tS\t1700000300\t\n\
+            Tom Lehrer\t\tThe Elements\t\t\tL\t1700000600\n";
+        let entries = parse_scrobbler_log(log).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0],
+            ScrobblerLogEntry {
+                artist: "Ed Sheeran".to_string(),
+                album: Some("÷ (Deluxe)".to_string()),
+                title: "Perfect".to_string(),
+                track_number: Some(5),
+                duration: Some(263),
+                listened_at: 1700000000,
+                recording_mbid: Some(
+                    Uuid::from_str("36855a5c-abcb-4740-9154-361af8c11ee1").unwrap()
+                ),
+            }
+        );
+        assert_eq!(entries[1].album, None);
+        assert_eq!(entries[1].recording_mbid, None);
+    }
+
+    #[test]
+    fn test_parse_scrobbler_log_bad_line() {
+        assert!(parse_scrobbler_log("#TZ/UTC\nEd Sheeran\tPerfect\n").is_err());
+    }
+
+    #[test]
+    fn test_fetch_listens_overlaps_page_boundary() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut server = rt.block_on(mockito::Server::new_async());
+        let first = server
+            .mock("GET", "/?max_ts=1701&count=2")
+            .with_body(
+                r#"{"payload": {"listens": [
+                    {"listened_at": 1700, "track_metadata": {"artist_name": "A", "track_name": "One"}},
+                    {"listened_at": 1600, "track_metadata": {"artist_name": "A", "track_name": "Two"}}
+                ]}}"#,
+            )
+            .create();
+        let second = server
+            .mock("GET", "/?max_ts=1601&count=2")
+            .with_body(
+                r#"{"payload": {"listens": [
+                    {"listened_at": 1600, "track_metadata": {"artist_name": "A", "track_name": "Two"}},
+                    {"listened_at": 1600, "track_metadata": {"artist_name": "B", "track_name": "Three"}}
+                ]}}"#,
+            )
+            .create();
+        let client = ListenbrainzClient::new("".to_string());
+        let listens = rt
+            .block_on(fetch_listens(&client, &server.url(), 2, 1000, 1700))
+            .unwrap();
+        first.assert();
+        second.assert();
+        assert_eq!(listens.len(), 3);
+        assert!(listens.contains(&(1600, "B".to_string(), "Three".to_string())));
+    }
+
+    #[test]
+    fn test_listen_to_json() {
+        let entry = ScrobblerLogEntry {
+            artist: "Tom Lehrer".to_string(),
+            album: None,
+            title: "The Elements".to_string(),
+            track_number: None,
+            duration: Some(90),
+            listened_at: 1700000600,
+            recording_mbid: None,
+        };
+        let mbid = Uuid::from_str("00066722-b23a-48e5-82e4-0470c82a2705").unwrap();
+        let listen = listen_to_json(&entry, Some(&mbid));
+        assert_eq!(listen["listened_at"], 1700000600);
+        assert_eq!(listen["track_metadata"]["track_name"], "The Elements");
+        assert!(listen["track_metadata"].get("release_name").is_none());
+        assert_eq!(
+            listen["track_metadata"]["additional_info"]["recording_mbid"],
+            "00066722-b23a-48e5-82e4-0470c82a2705"
+        );
+        assert_eq!(
+            listen["track_metadata"]["additional_info"]["duration_ms"],
+            90000
+        );
+    }
+}
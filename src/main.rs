@@ -1,54 +1,302 @@
+mod acoustid_client;
 mod audio_data;
+mod beets;
+mod duplicate_name;
+mod export;
 mod feedback;
+mod fingerprint;
+mod journal;
+mod library;
 mod listenbrainz_client;
+mod listens;
 mod paginator;
+mod path_pattern;
+mod players;
 mod playlist;
+mod rating;
+mod stats;
+mod tag_writer;
+mod upload;
 
-use crate::audio_data::AudioIDData;
-use crate::feedback::get_existing_feedback;
 use crate::listenbrainz_client::ListenbrainzClient;
-use crate::playlist::{
-    delete_items_from_playlist, get_current_playlists, get_current_user, mass_add_to_playlist,
-    FullExistingPlaylistResponse,
-};
-use anyhow::Result;
-use clap::{Parser, ValueEnum};
+use crate::playlist::get_current_user;
+use crate::upload::UploadSettings;
+use clap::{Parser, Subcommand, ValueEnum};
 use clap_verbosity_flag::{InfoLevel, Verbosity};
 use config::Config;
-use futures::stream::FuturesUnordered;
-use futures::{FutureExt, StreamExt};
 use indicatif::{ProgressBar, ProgressStyle};
-use inquire::Confirm;
-use log::{debug, error, info};
-use m3u::Entry;
+use log::error;
 use num_traits::ToPrimitive;
-use std::path::PathBuf;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 use uuid::Uuid;
 
 #[derive(Parser, Debug)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
-    file: PathBuf,
-    #[arg(short, long, default_value = "./config.toml")]
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(required = true)]
+    file: Option<PathBuf>,
+    #[arg(short, long, default_value = "./config.toml", global = true)]
     config: PathBuf,
-    playlist_name: String,
+    playlist_name: Option<String>,
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["playlist_name", "playlist_id", "journal"]
+    )]
+    playlist_name_from_source: bool,
     #[arg(value_enum, short, long)]
     feedback: Option<Feedback>,
+    #[arg(value_enum, long, default_value = "prompt", global = true)]
+    feedback_conflict: FeedbackConflict,
     #[arg(short, long, default_value_t = false)]
     public: bool,
+    #[arg(long, default_value_t = false, conflicts_with = "public")]
+    private: bool,
     #[command(flatten)]
     verbose: Verbosity<InfoLevel>,
-    #[arg(value_enum, short, long, default_value = "none")]
+    #[arg(value_enum, short, long, default_value = "none", global = true)]
     duplicate_action: DuplicateAction,
-    #[arg(short, long, default_value_t = false)]
+    #[arg(long, global = true)]
+    duplicate_name_template: Option<String>,
+    #[arg(long, conflicts_with = "duplicate_action", global = true)]
+    playlist_id: Option<Uuid>,
+    #[arg(short, long, default_value_t = false, global = true)]
     no_confirm: bool,
+    #[arg(long, default_value_t = false)]
+    write_tags: bool,
+    #[arg(long, default_value_t = false, requires = "write_tags")]
+    tag_dry_run: bool,
+    #[arg(long, default_value_t = false, requires = "write_tags")]
+    tag_backup: bool,
+    #[arg(long, default_value_t = false, requires = "write_tags")]
+    force_tags: bool,
+    #[arg(long, default_value_t = false, global = true)]
+    fingerprint: bool,
+    #[arg(long, default_value = "4", global = true)]
+    concurrency: NonZeroUsize,
+    #[arg(value_enum, long, default_value = "allow", global = true)]
+    dedupe: DedupePolicy,
+    #[arg(long, global = true)]
+    chunk_size: Option<NonZeroUsize>,
+    #[arg(long, default_value_t = false, global = true)]
+    resume: bool,
+    #[arg(long, global = true)]
+    journal: Option<PathBuf>,
+    #[arg(long)]
+    description: Option<String>,
+    #[arg(long = "collaborator")]
+    collaborators: Vec<String>,
+    #[arg(long)]
+    copied_from: Option<Uuid>,
+    #[arg(long)]
+    metadata_file: Option<PathBuf>,
+    #[arg(value_enum, long)]
+    track_annotation: Option<TrackAnnotation>,
     #[arg(long, hide = true)]
     markdown_help: bool,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage existing playlists without uploading a file
+    #[command(subcommand)]
+    Playlist(PlaylistCommand),
+    /// Sync ratings in local files with loves and hates on ListenBrainz
+    #[command(subcommand)]
+    Ratings(RatingsCommand),
+    /// Manage feedback without uploading a playlist
+    #[command(subcommand)]
+    Feedback(FeedbackCommand),
+    /// Manage the index of local music files used to find files by MBID
+    #[command(subcommand)]
+    Library(LibraryCommand),
+    /// Export a user's most listened recordings to a file or a ListenBrainz playlist
+    TopRecordings(TopRecordingsArgs),
+    /// Export the recordings recommended to a user to a file or a ListenBrainz playlist
+    Recommendations(RecommendationsArgs),
+    /// Export the tracks in a beets library matching a query to a file or a ListenBrainz playlist
+    Beets {
+        #[arg(required = true)]
+        query: Vec<String>,
+        #[command(flatten)]
+        output: ExportOutputArgs,
+    },
+    /// Upload a playlist stored by MPD, the same way as a playlist file
+    Mpd {
+        playlist: String,
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Upload a playlist from Strawberry or Clementine, the same way as a playlist file
+    Strawberry {
+        playlist: String,
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Submit the listens in an Audioscrobbler .scrobbler.log file, such as from Rockbox
+    ImportListens {
+        file: PathBuf,
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum FeedbackCommand {
+    /// Give feedback to the songs in a playlist and reset it on recordings no longer in it
+    Sync {
+        file: PathBuf,
+        #[arg(value_enum, short, long, default_value = "love")]
+        feedback: Feedback,
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+        #[arg(long, default_value = "25")]
+        max_removals: usize,
+    },
+    /// Export the recordings with some feedback to a file or a ListenBrainz playlist
+    Export(FeedbackExportArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct FeedbackExportArgs {
+    #[arg(value_enum, short, long, default_value = "love")]
+    score: Feedback,
+    #[command(flatten)]
+    output: ExportOutputArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct TopRecordingsArgs {
+    #[arg(value_enum, long, default_value = "all-time")]
+    range: StatsRange,
+    #[arg(long, default_value = "100")]
+    count: NonZeroUsize,
+    #[arg(long)]
+    user: Option<String>,
+    #[command(flatten)]
+    output: ExportOutputArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct RecommendationsArgs {
+    #[arg(long, default_value = "100")]
+    count: NonZeroUsize,
+    #[arg(long)]
+    user: Option<String>,
+    #[command(flatten)]
+    output: ExportOutputArgs,
+}
+
+/// Where to export a list of recordings to, shared by every command that makes one
+#[derive(clap::Args, Debug)]
+struct ExportOutputArgs {
+    #[arg(value_enum, long, default_value = "m3u")]
+    format: ExportFormat,
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    #[arg(long = "library")]
+    libraries: Vec<PathBuf>,
+    #[arg(long)]
+    name: Option<String>,
+}
+
 #[derive(ValueEnum, Debug, Clone, Copy)]
+#[clap(rename_all = "kebab-case")]
+enum StatsRange {
+    ThisWeek,
+    ThisMonth,
+    ThisYear,
+    Week,
+    Month,
+    Quarter,
+    Year,
+    HalfYearly,
+    AllTime,
+}
+
+impl StatsRange {
+    fn api_name(&self) -> String {
+        self.to_possible_value()
+            .expect("No ranges are skipped")
+            .get_name()
+            .replace('-', "_")
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[clap(rename_all = "lowercase")]
+enum ExportFormat {
+    M3u,
+    Jspf,
+    Csv,
+    Playlist,
+}
+
+#[derive(Subcommand, Debug)]
+enum LibraryCommand {
+    /// Add the audio files in directories to the index, only reading those that changed
+    Scan { directories: Vec<PathBuf> },
+}
+
+#[derive(Subcommand, Debug)]
+enum RatingsCommand {
+    /// Send feedback for the files in a playlist whose ratings disagree with ListenBrainz
+    Push {
+        file: PathBuf,
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+    /// Write ratings into the files in a playlist from their loves and hates on ListenBrainz
+    Pull {
+        file: PathBuf,
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PlaylistCommand {
+    /// Change the details of a playlist without touching its tracks
+    Edit {
+        playlist_id: Uuid,
+        #[arg(long)]
+        title: Option<String>,
+        #[arg(long)]
+        description: Option<String>,
+        #[arg(long, default_value_t = false)]
+        public: bool,
+        #[arg(long, default_value_t = false, conflicts_with = "public")]
+        private: bool,
+    },
+    /// Delete playlists by ID, or every playlist of yours with a title matching a pattern
+    Delete {
+        #[arg(required_unless_present = "title_pattern")]
+        playlist_ids: Vec<Uuid>,
+        #[arg(long, conflicts_with = "playlist_ids")]
+        title_pattern: Option<String>,
+    },
+    /// Delete playlists of yours that share a title, keeping one of each
+    PruneDuplicates {
+        #[arg(value_enum, long, default_value = "newest")]
+        keep: PruneKeep,
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+#[clap(rename_all = "kebab-case")]
+enum PruneKeep {
+    Newest,
+    MostTracks,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
 #[clap(rename_all = "lowercase")]
 enum Feedback {
     Love = 1,
@@ -56,6 +304,14 @@ enum Feedback {
     Neutral = 0,
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy)]
+#[clap(rename_all = "lowercase")]
+enum FeedbackConflict {
+    Skip,
+    Overwrite,
+    Prompt,
+}
+
 #[derive(ValueEnum, Debug, Clone, Copy)]
 #[clap(rename_all = "lowercase")]
 enum DuplicateAction {
@@ -65,6 +321,21 @@ enum DuplicateAction {
     Abort,
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy)]
+#[clap(rename_all = "kebab-case")]
+enum DedupePolicy {
+    KeepFirst,
+    KeepLast,
+    Allow,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+#[clap(rename_all = "lowercase")]
+enum TrackAnnotation {
+    Path,
+    Comment,
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
@@ -74,321 +345,31 @@ async fn main() {
         exit(0)
     }
 
-    let verbosity = args.verbose;
     env_logger::Builder::new()
-        .filter_level(verbosity.log_level_filter())
+        .filter_level(args.verbose.log_level_filter())
         .init();
 
     let settings = Config::builder()
-        .add_source(config::File::from(args.config))
+        .add_source(config::File::from(args.config.clone()))
         .build()
         .expect("Could not read configuration");
 
-    if !args.file.exists() {
-        error!("Given playlist file doesn't exist");
-        exit(1);
-    }
-
     let Ok(token) = settings.get_string("user_token") else {
         error!("Configuration does not contain a token!");
         exit(1)
     };
 
-    let mut client = ListenbrainzClient::new(token);
-
-    debug!("Testing token by resolving to user");
-    let user_name = match get_current_user(&mut client).await {
-        Ok(s) => s,
-        Err(e) => {
-            error!("Could not resolve token successfully: {}", e);
-            exit(1);
-        }
-    };
-    info!("This token belongs to {}!", &user_name);
-
-    let file_path = &args.file;
-    let playlist_entries = load_file_paths(file_path);
-    let number_of_files = playlist_entries.len();
-    info!("Found {} files in playlist", number_of_files);
-
-    if number_of_files == 0 {
-        error!("No files read from playlist, aborting");
-        exit(1);
-    }
-
-    let song_data: Vec<_> = playlist_entries
-        .into_iter()
-        .flat_map(audio_data::load_tags_from_file_path)
-        .collect();
-    let number_of_tagged_songs = song_data.len();
-    let percentage = calculate_percentage(&number_of_tagged_songs, &number_of_files)
-        .expect("Could not calculate percentage of tagged songs");
-    info!(
-        "{}/{} ({:.2}%) of songs had readable tags",
-        number_of_tagged_songs, number_of_files, percentage,
-    );
-
-    if number_of_tagged_songs == 0 {
-        error!("No tagged songs could be read, aborting");
-        exit(1);
-    }
+    let client = ListenbrainzClient::new(token);
 
-    info!("Resolving song tags to Musicbrainz IDs...");
-    let musicbrainz_ids = resolve_all_songs_for_mbids(&mut client, song_data).await;
-
-    let number_of_resolved_songs = musicbrainz_ids.len();
-    let percentage = calculate_percentage(&number_of_resolved_songs, &number_of_tagged_songs)
-        .expect("Could not calculate percentage of resolved songs");
-    info!(
-        "{}/{} ({:.2}%) of songs were resolved",
-        number_of_resolved_songs, number_of_tagged_songs, percentage,
-    );
-
-    if !args.no_confirm {
-        match Confirm::new("Do you want to continue with the matched songs?")
-            .with_default(true)
-            .prompt()
-        {
-            Ok(true) => {
-                info!("Continuing");
-            }
-            Ok(false) => {
-                info!("Aborting");
-                exit(1)
-            }
-            Err(_) => {
-                error!("Error with questionaire");
-            }
-        }
-    }
+    let upload_settings = UploadSettings::load(&args, &settings);
 
-    debug!("Retrieving existing playlists");
-    let current_playlists = match get_current_playlists(&mut client, &user_name).await {
-        Ok(playlists) => playlists,
-        Err(e) => {
-            error!("Could not retrieve existing playlists: {}", e);
-            exit(1)
-        }
-    };
-    debug!(
-        "Found {} existing playlists on account",
-        current_playlists.len()
-    );
-    let mut playlist_name = args.playlist_name.clone();
-    let searched_playlist = current_playlists
-        .iter()
-        .find(|p| p.title == args.playlist_name);
-    match searched_playlist {
-        Some(p) => {
-            let p = match FullExistingPlaylistResponse::convert_simple_playlist_response_to_full(
-                &mut client,
-                p,
-            )
-            .await
-            {
-                Err(e) => {
-                    error!(
-                        "Could not find more detailed information on possible duplicate playlist: {}",
-                        e
-                    );
-                    exit(1)
-                }
-                Ok(p) => p,
-            };
-            info!("Found a duplicate playlist, enacting duplicate policy");
-            match args.duplicate_action {
-                DuplicateAction::None => {
-                    // Just submit new playlist
-                    submit_new_playlist(&mut client, args.public, &musicbrainz_ids, playlist_name)
-                        .await;
-                }
-                DuplicateAction::Overwrite => {
-                    if p.number_of_tracks > 0 {
-                        let deletion_request = delete_items_from_playlist(
-                            &mut client,
-                            &p.identifier,
-                            0,
-                            p.number_of_tracks + 1,
-                        )
-                        .await;
-                        match deletion_request {
-                            Ok(()) => {}
-                            Err(e) => {
-                                error!(
-                                    "Could not delete items from playlist to overwrite it: {}",
-                                    e
-                                );
-                                exit(1)
-                            }
-                        }
-                    } else {
-                        debug!("Existing playlist already has no tracks");
-                    }
-                    let insertion_request =
-                        mass_add_to_playlist(&mut client, &p.identifier, &musicbrainz_ids).await;
-                    match insertion_request {
-                        Ok(()) => {
-                            info!("Replaced songs in playlist with ID {}", p.identifier);
-                        }
-                        Err(e) => {
-                            error!("Could not insert new items into playlist: {}", e);
-                            exit(1)
-                        }
-                    }
-                }
-                DuplicateAction::Number => {
-                    for i in 1.. {
-                        let prospective_title = format!("{}_{}", args.playlist_name, i);
-                        if current_playlists
-                            .iter()
-                            .any(|p| p.title == prospective_title)
-                        {
-                            continue;
-                        }
-                        playlist_name = prospective_title;
-                    }
-                    submit_new_playlist(&mut client, args.public, &musicbrainz_ids, playlist_name)
-                        .await;
-                }
-                DuplicateAction::Abort => {
-                    error!("Duplicate action says to abort!");
-                    exit(1)
-                }
-            }
-        }
-        None => {
-            info!("No duplicate playlists found");
-            submit_new_playlist(&mut client, args.public, &musicbrainz_ids, playlist_name).await;
-        }
-    }
-
-    match args.feedback {
-        None => {}
-        Some(f) => {
-            let given_feedback = get_existing_feedback(&mut client, &user_name, f)
-                .await
-                .expect("Could not get existing feedback");
-            let filtered_musicbrainz_ids: Vec<_> = musicbrainz_ids
-                .iter()
-                .filter(|i| !given_feedback.contains(*i))
-                .collect();
-            let filtered_len = filtered_musicbrainz_ids.len();
-            let total_len = musicbrainz_ids.len();
-            let correct_len = total_len - filtered_len;
-            let percentage = calculate_percentage(&correct_len, &total_len).unwrap();
-            if filtered_len == 0 {
-                info!("All songs in playlist already have the correct feedback");
-                exit(0)
-            } else if filtered_len == total_len {
-                info!("Sending feedback for songs in playlist...");
-            } else {
-                info!(
-                    "{}/{} ({:.2}%) of songs already have the correct feedback",
-                    correct_len, total_len, percentage
-                );
-                info!("Sending feedback for remaining songs in playlist...");
-            }
-            give_feedback_on_all_songs(&mut client, filtered_musicbrainz_ids, f).await;
-        }
-    }
-}
-
-async fn submit_new_playlist(
-    listenbrainz_client: &mut ListenbrainzClient,
-    public: bool,
-    musicbrainz_ids: &Vec<Uuid>,
-    playlist_name: String,
-) {
-    debug!("Submitting new playlist");
-    match playlist::submit_playlist(listenbrainz_client, musicbrainz_ids, playlist_name, public)
-        .await
-    {
-        Ok(r) => {
-            info!("Playlist created with ID {}", r.playlist_mbid);
-        }
-        Err(e) => {
-            error!("Could not create playlist: {}", e);
-        }
+    if let Some(command) = &args.command {
+        run_command(&client, command, &settings, &args, &upload_settings).await;
+        exit(0)
     }
-}
 
-async fn give_feedback_on_all_songs(
-    listenbrainz_client: &mut ListenbrainzClient,
-    musicbrainz_ids: Vec<&Uuid>,
-    feedback: Feedback,
-) {
-    let progress_bar = make_progress_bar(musicbrainz_ids.len());
-    let listenbrainz_client = Arc::new(Mutex::new(listenbrainz_client));
-    let futures: FuturesUnordered<_> = musicbrainz_ids
-        .iter()
-        .map(|mbid| {
-            let pb = Arc::clone(&progress_bar);
-            let listenbrainz_client = Arc::clone(&listenbrainz_client);
-            async move {
-                let out = feedback::give_song_feedback_for_mbid(
-                    *listenbrainz_client.lock().await,
-                    mbid,
-                    feedback,
-                )
-                .await;
-                pb.inc(1);
-                out
-            }
-            .boxed()
-        })
-        .collect();
-
-    let results: Vec<Result<()>> = futures.collect().await;
-    for result in results {
-        match result {
-            Ok(()) => {}
-            Err(e) => {
-                error!("Could not give feedback on song: {}", e);
-            }
-        }
-    }
-}
-async fn resolve_all_songs_for_mbids(
-    listenbrainz_client: &mut ListenbrainzClient,
-    song_data: Vec<AudioIDData>,
-) -> Vec<Uuid> {
-    let progress_bar = make_progress_bar(song_data.len());
-    let listenbrainz_client = Arc::new(Mutex::new(listenbrainz_client));
-    let futures: FuturesUnordered<_> = song_data
-        .into_iter()
-        .map(|data| {
-            let pb = Arc::clone(&progress_bar);
-            let listenbrainz_client = Arc::clone(&listenbrainz_client);
-            async move {
-                let out = match data {
-                    AudioIDData::Mbid(mbid) => Ok(mbid),
-                    AudioIDData::AudioFileData(d) => {
-                        audio_data::get_musicbrainz_id_for_audio_data(
-                            *listenbrainz_client.lock().await,
-                            d,
-                        )
-                        .await
-                    }
-                };
-                pb.inc(1);
-                out
-            }
-            .boxed()
-        })
-        .collect();
-
-    let musicbrainz_ids: Vec<Result<Uuid>> = futures.collect().await;
-
-    musicbrainz_ids
-        .into_iter()
-        .filter_map(|result| match result {
-            Ok(s) => Some(s),
-            Err(e) => {
-                error!("Could not resolve song: {}", e);
-                None
-            }
-        })
-        .collect()
+    let file = args.file.clone().expect("Playlist file is required");
+    upload::upload_playlists(&client, &settings, &args, &upload_settings, &file).await;
 }
 
 fn make_progress_bar(length: usize) -> Arc<ProgressBar> {
@@ -407,17 +388,99 @@ where
     }
 }
 
-fn load_file_paths(file_path: &PathBuf) -> Vec<PathBuf> {
-    let playlist_entries: Vec<PathBuf> = m3u::Reader::open(file_path)
-        .expect("Could not read playlist file")
-        .entries()
-        .map(|e| e.expect("Could not read M3U entry"))
-        .filter_map(|e| match e {
-            Entry::Path(path) => Some(path),
-            Entry::Url(_) => None,
-        })
-        .collect();
-    playlist_entries
+/// The visibility to give an existing playlist, which is only changed when asked for
+fn visibility(public: bool, private: bool) -> Option<bool> {
+    match (public, private) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+async fn run_command(
+    client: &ListenbrainzClient,
+    command: &Command,
+    settings: &Config,
+    args: &Args,
+    upload: &UploadSettings,
+) {
+    match command {
+        Command::Feedback(FeedbackCommand::Sync {
+            file,
+            feedback,
+            dry_run,
+            max_removals,
+        }) => {
+            feedback::sync_feedback(
+                client,
+                settings,
+                args,
+                file,
+                *feedback,
+                *dry_run,
+                *max_removals,
+            )
+            .await;
+        }
+        Command::Feedback(FeedbackCommand::Export(export)) => {
+            export::export_feedback(client, settings, args, upload, export).await;
+        }
+        Command::TopRecordings(top) => {
+            export::export_top_recordings(client, settings, args, upload, top).await;
+        }
+        Command::Recommendations(recommendations) => {
+            export::export_recommendations(client, settings, args, upload, recommendations).await;
+        }
+        Command::Beets { query, output } => {
+            export::export_beets_query(client, settings, args, upload, &query.join(" "), output)
+                .await;
+        }
+        Command::Mpd { playlist, name } => {
+            players::upload_mpd_playlist(client, settings, args, upload, playlist, name.as_deref())
+                .await;
+        }
+        Command::Strawberry { playlist, name } => {
+            players::upload_strawberry_playlist(
+                client,
+                settings,
+                args,
+                upload,
+                playlist,
+                name.as_deref(),
+            )
+            .await;
+        }
+        Command::Library(LibraryCommand::Scan { directories }) => {
+            library::scan_library(settings, args, directories);
+        }
+        Command::ImportListens { file, dry_run } => {
+            listens::import_listens(client, args, file, *dry_run).await;
+        }
+        Command::Ratings(RatingsCommand::Push { file, dry_run }) => {
+            rating::push_ratings(client, settings, args, file, *dry_run).await;
+        }
+        Command::Ratings(RatingsCommand::Pull { file, dry_run }) => {
+            rating::pull_ratings(client, settings, file, *dry_run).await;
+        }
+        Command::Playlist(command) => {
+            playlist::run_playlist_command(client, command, args.no_confirm).await;
+        }
+    }
+}
+
+async fn resolve_user_name(client: &ListenbrainzClient) -> String {
+    match get_current_user(client).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Could not resolve token successfully: {}", e);
+            exit(1);
+        }
+    }
+}
+
+fn home_path(relative: &str) -> PathBuf {
+    let home = std::env::var("HOME").unwrap_or_default();
+    Path::new(&home).join(relative)
 }
 
 #[cfg(test)]
@@ -425,10 +488,30 @@ mod test {
     use crate::*;
 
     #[test]
-    fn test_load_songs_from_playlist() {
-        let file_path = &PathBuf::from("./tests/test_playlist_1.m3u");
-        let result = load_file_paths(file_path);
-
-        assert_eq!(result.len(), 4);
+    fn test_parse_playlist_edit_command() {
+        let args = Args::try_parse_from([
+            "listenbrainz-playlist-uploader",
+            "playlist",
+            "edit",
+            "36855a5c-abcb-4740-9154-361af8c11ee1",
+            "--title",
+            "Renamed",
+            "--private",
+        ])
+        .unwrap();
+        assert!(args.file.is_none());
+        match args.command {
+            Some(Command::Playlist(PlaylistCommand::Edit {
+                title,
+                public,
+                private,
+                ..
+            })) => {
+                assert_eq!(title.as_deref(), Some("Renamed"));
+                assert_eq!(visibility(public, private), Some(false));
+            }
+            _ => panic!("Expected a playlist edit command"),
+        }
+        assert!(Args::try_parse_from(["listenbrainz-playlist-uploader"]).is_err());
     }
 }
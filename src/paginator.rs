@@ -1,5 +1,21 @@
+use crate::listenbrainz_client::ListenbrainzClient;
+use anyhow::Result;
+use log::warn;
+use std::time::Duration;
+use tokio::time::sleep;
 use url::Url;
 
+const PAGE_ATTEMPTS: u32 = 3;
+const PAGE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// One page of a listing. `count` is how many entries the response held, which can be more than
+/// the items kept from it.
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub count: usize,
+    pub total_count: Option<usize>,
+}
+
 pub struct ListenbrainzPaginator {
     base_url: String,
     current_position: usize,
@@ -33,10 +49,80 @@ impl Iterator for ListenbrainzPaginator {
     }
 }
 
+/// Gets every item from an endpoint taking `count` and `offset` parameters. This stops at
+/// `total_count` if the endpoint gives one, and otherwise at the first short page.
+pub async fn fetch_all_pages<T>(
+    listenbrainz_client: &ListenbrainzClient,
+    base_url: &str,
+    count_per_page: usize,
+    parse_page: impl Fn(&str) -> Result<Page<T>>,
+) -> Result<Vec<T>> {
+    let mut items = Vec::new();
+    let mut seen = 0;
+    for url in ListenbrainzPaginator::new(base_url, 0, count_per_page) {
+        let page = parse_page(&fetch_page(listenbrainz_client, url).await?)?;
+        seen += page.count;
+        items.extend(page.items);
+        let finished = match page.total_count {
+            Some(total_count) => seen >= total_count || page.count == 0,
+            None => page.count < count_per_page,
+        };
+        if finished {
+            break;
+        }
+    }
+    Ok(items)
+}
+
+/// Gets a single page, retrying it a few times unless the request itself was refused
+pub async fn fetch_page(listenbrainz_client: &ListenbrainzClient, url: Url) -> Result<String> {
+    let mut attempt = 1;
+    loop {
+        let result = async {
+            let response = listenbrainz_client
+                .take_request_builder(listenbrainz_client.request_client.get(url.clone()))
+                .await?;
+            Ok(response.error_for_status()?.text().await?)
+        }
+        .await;
+        match result {
+            Ok(text) => return Ok(text),
+            Err(e) if attempt < PAGE_ATTEMPTS && is_retryable(&e) => {
+                warn!("Could not get page {} (attempt {}): {}", url, attempt, e);
+                sleep(PAGE_RETRY_DELAY * attempt).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e.context(format!("Could not get page {url}"))),
+        }
+    }
+}
+
+fn is_retryable(error: &anyhow::Error) -> bool {
+    !error
+        .downcast_ref::<reqwest::Error>()
+        .and_then(|e| e.status())
+        .is_some_and(|s| s.is_client_error())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    fn parse_numbers(json: &str) -> Result<Page<u64>> {
+        let data: serde_json::Value = serde_json::from_str(json)?;
+        let items: Vec<u64> = data["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|i| i.as_u64())
+            .collect();
+        Ok(Page {
+            count: items.len(),
+            items,
+            total_count: data["total_count"].as_u64().map(|t| t as usize),
+        })
+    }
+
     #[test]
     fn test_paginator() {
         let mut test = ListenbrainzPaginator::new("https://www.example.com/", 0, 5);
@@ -53,4 +139,83 @@ mod test {
             test.next().unwrap().as_str()
         );
     }
+
+    #[test]
+    fn test_fetch_all_pages_stops_on_short_page() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut server = rt.block_on(mockito::Server::new_async());
+        let first = server
+            .mock("GET", "/?count=2&offset=0")
+            .with_body(r#"{"items": [1, 2]}"#)
+            .create();
+        let second = server
+            .mock("GET", "/?count=2&offset=2")
+            .with_body(r#"{"items": [3]}"#)
+            .create();
+        let client = ListenbrainzClient::new("".to_string());
+        let result = rt
+            .block_on(fetch_all_pages(&client, &server.url(), 2, parse_numbers))
+            .unwrap();
+        first.assert();
+        second.assert();
+        assert_eq!(result, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_fetch_all_pages_stops_at_total_count() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut server = rt.block_on(mockito::Server::new_async());
+        let first = server
+            .mock("GET", "/?count=2&offset=0")
+            .with_body(r#"{"items": [1, 2], "total_count": 4}"#)
+            .create();
+        let second = server
+            .mock("GET", "/?count=2&offset=2")
+            .with_body(r#"{"items": [3, 4], "total_count": 4}"#)
+            .create();
+        let client = ListenbrainzClient::new("".to_string());
+        let result = rt
+            .block_on(fetch_all_pages(&client, &server.url(), 2, parse_numbers))
+            .unwrap();
+        first.assert();
+        second.assert();
+        assert_eq!(result, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_fetch_page_retries_server_errors() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut server = rt.block_on(mockito::Server::new_async());
+        let failure = server
+            .mock("GET", "/?count=2&offset=0")
+            .with_status(500)
+            .expect(1)
+            .create();
+        let success = server
+            .mock("GET", "/?count=2&offset=0")
+            .with_body(r#"{"items": [1]}"#)
+            .create();
+        let client = ListenbrainzClient::new("".to_string());
+        let result = rt
+            .block_on(fetch_all_pages(&client, &server.url(), 2, parse_numbers))
+            .unwrap();
+        failure.assert();
+        success.assert();
+        assert_eq!(result, vec![1]);
+    }
+
+    #[test]
+    fn test_fetch_page_does_not_retry_client_errors() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut server = rt.block_on(mockito::Server::new_async());
+        let failure = server
+            .mock("GET", "/?count=2&offset=0")
+            .with_status(404)
+            .expect(1)
+            .create();
+        let client = ListenbrainzClient::new("".to_string());
+        let result = rt.block_on(fetch_all_pages(&client, &server.url(), 2, parse_numbers));
+        failure.assert();
+        assert!(result.is_err());
+    }
 }
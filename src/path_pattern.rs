@@ -0,0 +1,145 @@
+use crate::audio_data::AudioFileData;
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use std::path::Path;
+
+pub struct PathPattern {
+    regex: Regex,
+    number_of_components: usize,
+}
+
+impl PathPattern {
+    /// Builds a pattern such as `{artist}/{album}/{track} {title}`, matched against the end of
+    /// a file path with the extension removed
+    pub fn new(pattern: &str) -> Result<Self> {
+        let mut regex = String::from("^");
+        let mut remaining = pattern;
+        let mut has_artist = false;
+        let mut has_title = false;
+        while let Some(start) = remaining.find('{') {
+            regex.push_str(&regex::escape(&remaining[..start]));
+            let end = remaining[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("Unclosed placeholder in pattern '{}'", pattern))?
+                + start;
+            let group = match &remaining[start + 1..end] {
+                "artist" => {
+                    has_artist = true;
+                    r"(?P<artist>[^/]+?)"
+                }
+                "title" => {
+                    has_title = true;
+                    r"(?P<title>[^/]+?)"
+                }
+                "album" => r"(?P<album>[^/]+?)",
+                "track" => r"\d+",
+                "ignore" => r"[^/]*?",
+                other => return Err(anyhow!("Unknown placeholder '{{{}}}' in pattern", other)),
+            };
+            regex.push_str(group);
+            remaining = &remaining[end + 1..];
+        }
+        regex.push_str(&regex::escape(remaining));
+        regex.push('$');
+
+        if !(has_artist && has_title) {
+            return Err(anyhow!(
+                "Pattern '{}' needs both {{artist}} and {{title}}",
+                pattern
+            ));
+        }
+        Ok(PathPattern {
+            regex: Regex::new(&regex)?,
+            number_of_components: pattern.matches('/').count() + 1,
+        })
+    }
+
+    pub fn infer_audio_file_data(&self, file: &Path) -> Option<AudioFileData> {
+        let stem = file.with_extension("");
+        let components: Vec<_> = stem
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect();
+        if components.len() < self.number_of_components {
+            return None;
+        }
+        let relevant_path = components[components.len() - self.number_of_components..].join("/");
+        let captures = self.regex.captures(&relevant_path)?;
+        Some(AudioFileData {
+            artist: captures.name("artist")?.as_str().trim().to_string(),
+            title: captures.name("title")?.as_str().trim().to_string(),
+            album: captures
+                .name("album")
+                .map(|a| a.as_str().trim().to_string()),
+        })
+    }
+}
+
+pub fn infer_from_patterns(patterns: &[PathPattern], file: &Path) -> Option<AudioFileData> {
+    patterns.iter().find_map(|p| p.infer_audio_file_data(file))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_infer_artist_album_track_title() {
+        let pattern = PathPattern::new("{artist}/{album}/{track} {title}").unwrap();
+        let result = pattern.infer_audio_file_data(&PathBuf::from(
+            "/music/Ethel Cain/Preacher’s Daughter/03 A House in Nebraska.flac",
+        ));
+        assert_eq!(
+            result,
+            Some(AudioFileData {
+                artist: "Ethel Cain".to_string(),
+                title: "A House in Nebraska".to_string(),
+                album: Some("Preacher’s Daughter".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn test_infer_artist_title_in_filename() {
+        let pattern = PathPattern::new("{artist} - {title}").unwrap();
+        let result =
+            pattern.infer_audio_file_data(&PathBuf::from("/downloads/Ed Sheeran - Perfect.mp3"));
+        assert_eq!(
+            result,
+            Some(AudioFileData {
+                artist: "Ed Sheeran".to_string(),
+                title: "Perfect".to_string(),
+                album: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_infer_no_match() {
+        let pattern = PathPattern::new("{artist}/{album}/{track} {title}").unwrap();
+        let result = pattern.infer_audio_file_data(&PathBuf::from("/music/Untitled.flac"));
+        assert_eq!(result, None);
+    }
+
+    #[test]
+    fn test_infer_first_matching_pattern() {
+        let patterns = vec![
+            PathPattern::new("{artist} - {title}").unwrap(),
+            PathPattern::new("{artist}/{album}/{track} {title}").unwrap(),
+        ];
+        let result = infer_from_patterns(
+            &patterns,
+            &PathBuf::from("/music/Christina Perri/A Thousand Years/01 A Thousand Years.mp3"),
+        )
+        .unwrap();
+        assert_eq!(result.artist, "Christina Perri");
+        assert_eq!(result.title, "A Thousand Years");
+    }
+
+    #[test]
+    fn test_pattern_needs_artist_and_title() {
+        assert!(PathPattern::new("{album}/{title}").is_err());
+        assert!(PathPattern::new("{artist}/{nonsense}").is_err());
+    }
+}
@@ -0,0 +1,532 @@
+use crate::audio_data::{AudioFileData, AudioIDData};
+use crate::listenbrainz_client::ListenbrainzClient;
+use crate::upload::{upload_playlist_file, UploadContext, UploadSettings};
+use crate::{home_path, resolve_user_name, Args};
+use anyhow::{anyhow, Result};
+use config::Config;
+use log::{error, info};
+use m3u::Entry;
+use roxmltree::{Document, Node, ParsingOptions};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::str::FromStr;
+use url::Url;
+use uuid::Uuid;
+
+/// A track in a music player's playlist, with whatever the player knows about it
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PlayerTrack {
+    pub location: PathBuf,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub recording_mbid: Option<Uuid>,
+}
+
+impl PlayerTrack {
+    /// Uses what the player stored, if that's enough to look the track up
+    pub fn audio_id_data(&self) -> Option<AudioIDData> {
+        if let Some(mbid) = self.recording_mbid {
+            return Some(AudioIDData::Mbid(mbid));
+        }
+        Some(AudioIDData::AudioFileData(AudioFileData {
+            artist: self.artist.clone()?,
+            title: self.title.clone()?,
+            album: self.album.clone(),
+        }))
+    }
+
+    /// A track that is only known by its file
+    pub fn from_location(location: PathBuf) -> Self {
+        PlayerTrack {
+            location,
+            ..Default::default()
+        }
+    }
+}
+
+/// A playlist from a music player, with the tracks in it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerPlaylist {
+    pub name: String,
+    pub tracks: Vec<PlayerTrack>,
+}
+
+/// Reads the playlists of an iTunes or Music.app `Library.xml`, or a Rhythmbox `playlists.xml`.
+/// Other files aren't libraries, so give `None`.
+pub fn read_playlist_library(path: &Path) -> Result<Option<Vec<PlayerPlaylist>>> {
+    let is_xml = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("xml"));
+    if !is_xml {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(path)?;
+    // iTunes libraries declare the plist DTD
+    let options = ParsingOptions {
+        allow_dtd: true,
+        ..Default::default()
+    };
+    let document = Document::parse_with_options(&contents, options)?;
+    let root = document.root_element();
+    match root.tag_name().name() {
+        "plist" => read_itunes_library(root).map(Some),
+        "rhythmdb-playlists" => Ok(Some(read_rhythmbox_playlists(root))),
+        other => Err(anyhow!("{:?} has unknown <{}> playlists", path, other)),
+    }
+}
+
+fn read_itunes_library(plist: Node) -> Result<Vec<PlayerPlaylist>> {
+    let library = plist
+        .children()
+        .find(|n| n.has_tag_name("dict"))
+        .map(plist_dict)
+        .ok_or_else(|| anyhow!("iTunes library has no dictionary"))?;
+    let mut locations = HashMap::new();
+    if let Some(tracks) = library.get("Tracks") {
+        for track in plist_dict(*tracks).into_values() {
+            let track = plist_dict(track);
+            let id = track.get("Track ID").and_then(|n| n.text());
+            let location = track.get("Location").and_then(|n| n.text());
+            if let (Some(id), Some(location)) = (id, location.and_then(file_url_to_path)) {
+                locations.insert(id.to_string(), location);
+            }
+        }
+    }
+    let mut playlists = Vec::new();
+    let items = library
+        .get("Playlists")
+        .map(|p| p.children().filter(|n| n.has_tag_name("dict")));
+    for playlist in items.into_iter().flatten().map(plist_dict) {
+        // The whole library, built-in views like Music or Podcasts, and folders aren't playlists
+        if ["Master", "Distinguished Kind", "Folder"]
+            .iter()
+            .any(|k| playlist.contains_key(k))
+        {
+            continue;
+        }
+        let Some(name) = playlist.get("Name").and_then(|n| n.text()) else {
+            continue;
+        };
+        let tracks = playlist
+            .get("Playlist Items")
+            .map(|i| i.children().filter(|n| n.has_tag_name("dict")))
+            .into_iter()
+            .flatten()
+            .filter_map(|item| plist_dict(item).get("Track ID").and_then(|n| n.text()))
+            .filter_map(|id| locations.get(id).cloned())
+            .map(PlayerTrack::from_location)
+            .collect();
+        playlists.push(PlayerPlaylist {
+            name: name.to_string(),
+            tracks,
+        });
+    }
+    Ok(playlists)
+}
+
+// A plist dictionary is a <key> element followed by its value, for each entry
+fn plist_dict<'a, 'input>(dict: Node<'a, 'input>) -> HashMap<&'a str, Node<'a, 'input>> {
+    let mut entries = HashMap::new();
+    let mut elements = dict.children().filter(|n| n.is_element());
+    while let (Some(key), Some(value)) = (elements.next(), elements.next()) {
+        if let Some(key) = key.text() {
+            entries.insert(key, value);
+        }
+    }
+    entries
+}
+
+fn read_rhythmbox_playlists(root: Node) -> Vec<PlayerPlaylist> {
+    root.children()
+        .filter(|n| n.has_tag_name("playlist"))
+        // The play queue is kept with the playlists
+        .filter(|n| n.attribute("type") != Some("queue"))
+        .filter_map(|playlist| {
+            let tracks = playlist
+                .children()
+                .filter(|n| n.has_tag_name("location"))
+                .filter_map(|n| n.text().and_then(file_url_to_path))
+                .map(PlayerTrack::from_location)
+                .collect();
+            Some(PlayerPlaylist {
+                name: playlist.attribute("name")?.to_string(),
+                tracks,
+            })
+        })
+        .collect()
+}
+
+/// Reads a stored MPD playlist, whose paths are relative to the music directory
+pub fn read_mpd_playlist(
+    playlist_directory: &Path,
+    music_directory: &Path,
+    name: &str,
+) -> Result<Vec<PlayerTrack>> {
+    let playlist_file = playlist_directory.join(format!("{name}.m3u"));
+    let mut reader = m3u::Reader::open(&playlist_file)
+        .map_err(|e| anyhow!("Could not open MPD playlist {:?}: {}", playlist_file, e))?;
+    let mut tracks = Vec::new();
+    for entry in reader.entries() {
+        match entry? {
+            Entry::Path(path) => {
+                tracks.push(PlayerTrack::from_location(music_directory.join(path)))
+            }
+            // Streams can't be matched to recordings
+            Entry::Url(_) => {}
+        }
+    }
+    Ok(tracks)
+}
+
+/// Reads a playlist from the database of Strawberry, or of Clementine which it was forked from
+pub fn read_strawberry_playlist(database: &Path, name: &str) -> Result<Vec<PlayerTrack>> {
+    let connection = Connection::open_with_flags(database, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let playlist_id: i64 = connection
+        .query_row(
+            "SELECT ROWID FROM playlists WHERE name = ?1",
+            params![name],
+            |r| r.get(0),
+        )
+        .optional()?
+        .ok_or_else(|| anyhow!("No playlist called '{}' in {:?}", name, database))?;
+
+    // Songs from the collection are only stored as a reference to the songs table
+    let item_columns = table_columns(&connection, "playlist_items")?;
+    let song_columns = table_columns(&connection, "songs")?;
+    let collection_column = ["collection_id", "library_id"]
+        .into_iter()
+        .find(|c| item_columns.contains(*c));
+    let column = |name: &str, alternatives: &[&str]| -> String {
+        let pick = |columns: &HashSet<String>| {
+            std::iter::once(name)
+                .chain(alternatives.iter().copied())
+                .find(|c| columns.contains(*c))
+        };
+        let item = pick(&item_columns).map(|c| format!("NULLIF(i.{c}, '')"));
+        let song = pick(&song_columns)
+            .filter(|_| collection_column.is_some())
+            .map(|c| format!("NULLIF(s.{c}, '')"));
+        match (item, song) {
+            (Some(i), Some(s)) => format!("COALESCE({i}, {s})"),
+            (Some(c), None) | (None, Some(c)) => c,
+            (None, None) => "NULL".to_string(),
+        }
+    };
+    let join = match collection_column {
+        Some(c) => format!("LEFT JOIN songs s ON s.ROWID = i.{c}"),
+        None => String::new(),
+    };
+    let query = format!(
+        "SELECT {}, {}, {}, {}, {} FROM playlist_items i {join} WHERE i.playlist = ?1 \
+        ORDER BY i.ROWID",
+        column("url", &["filename"]),
+        column("artist", &[]),
+        column("title", &[]),
+        column("album", &[]),
+        column("musicbrainz_recording_id", &[]),
+    );
+    let mut statement = connection.prepare(&query)?;
+    let rows = statement.query_map(params![playlist_id], |r| {
+        Ok((
+            r.get::<_, Option<String>>(0)?,
+            r.get::<_, Option<String>>(1)?,
+            r.get::<_, Option<String>>(2)?,
+            r.get::<_, Option<String>>(3)?,
+            r.get::<_, Option<String>>(4)?,
+        ))
+    })?;
+    let mut tracks = Vec::new();
+    for row in rows {
+        let (location, artist, title, album, mbid) = row?;
+        let location = location.unwrap_or_default();
+        tracks.push(PlayerTrack {
+            location: location_to_path(&location),
+            artist,
+            title,
+            album,
+            recording_mbid: mbid.and_then(|m| Uuid::from_str(&m).ok()),
+        });
+    }
+    Ok(tracks)
+}
+
+fn table_columns(connection: &Connection, table: &str) -> Result<HashSet<String>> {
+    let columns = connection
+        .prepare("SELECT name FROM pragma_table_info(?1)")?
+        .query_map(params![table], |r| r.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(columns)
+}
+
+// Locations are stored as URLs, which are only paths when they're local files
+fn location_to_path(location: &str) -> PathBuf {
+    file_url_to_path(location).unwrap_or_else(|| PathBuf::from(location))
+}
+
+fn file_url_to_path(location: &str) -> Option<PathBuf> {
+    Url::parse(location.trim())
+        .ok()
+        .filter(|u| u.scheme() == "file")
+        .and_then(|u| u.to_file_path().ok())
+}
+
+/// Uploads a playlist stored by MPD, whose paths are relative to its music directory
+pub async fn upload_mpd_playlist(
+    client: &ListenbrainzClient,
+    settings: &Config,
+    args: &Args,
+    upload: &UploadSettings,
+    playlist: &str,
+    name: Option<&str>,
+) {
+    let Ok(music_directory) = settings.get_string("mpd_music_directory") else {
+        error!("Reading MPD playlists needs mpd_music_directory in the configuration!");
+        exit(1)
+    };
+    let playlist_directory = settings
+        .get_string("mpd_playlist_directory")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| home_path(".local/share/mpd/playlists"));
+    let tracks = read_mpd_playlist(&playlist_directory, Path::new(&music_directory), playlist)
+        .unwrap_or_else(|e| {
+            error!("Could not read the MPD playlist: {}", e);
+            exit(1)
+        });
+    let source = PlayerPlaylist {
+        name: playlist.to_string(),
+        tracks,
+    };
+    let file = playlist_directory.join(format!("{playlist}.m3u"));
+    upload_player_playlist(client, settings, args, upload, &file, &source, name).await;
+}
+
+pub async fn upload_strawberry_playlist(
+    client: &ListenbrainzClient,
+    settings: &Config,
+    args: &Args,
+    upload: &UploadSettings,
+    playlist: &str,
+    name: Option<&str>,
+) {
+    let database = settings
+        .get_string("strawberry_database")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| home_path(".local/share/strawberry/strawberry/strawberry.db"));
+    let tracks = read_strawberry_playlist(&database, playlist).unwrap_or_else(|e| {
+        error!("Could not read the Strawberry playlist: {}", e);
+        exit(1)
+    });
+    let source = PlayerPlaylist {
+        name: playlist.to_string(),
+        tracks,
+    };
+    upload_player_playlist(client, settings, args, upload, &database, &source, name).await;
+}
+
+/// Uploads a playlist kept by a music player, which is stored in `file`, named `title` if given
+pub async fn upload_player_playlist(
+    client: &ListenbrainzClient,
+    settings: &Config,
+    args: &Args,
+    upload: &UploadSettings,
+    file: &PathBuf,
+    source: &PlayerPlaylist,
+    title: Option<&str>,
+) {
+    info!("Found {} tracks in '{}'", source.tracks.len(), source.name);
+    let user_name = resolve_user_name(client).await;
+    let context = UploadContext::load(client, settings, args, user_name).await;
+    if let Err(e) =
+        upload_playlist_file(client, args, upload, &context, file, Some(source), title).await
+    {
+        error!("{}", e);
+        exit(1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_mpd_playlist() {
+        let directory = std::env::temp_dir().join(format!("lpu-{}-mpd", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(
+            directory.join("Road Trip.m3u"),
+            "Ed Sheeran/Divide/Perfect.flac\n/elsewhere/Song.mp3\nhttp://radio.example/stream\n",
+        )
+        .unwrap();
+        let tracks = read_mpd_playlist(&directory, Path::new("/music"), "Road Trip").unwrap();
+        let locations: Vec<PathBuf> = tracks.into_iter().map(|t| t.location).collect();
+        assert_eq!(
+            locations,
+            vec![
+                PathBuf::from("/music/Ed Sheeran/Divide/Perfect.flac"),
+                PathBuf::from("/elsewhere/Song.mp3")
+            ]
+        );
+        assert!(read_mpd_playlist(&directory, Path::new("/music"), "Missing").is_err());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_read_strawberry_playlist() {
+        let path = std::env::temp_dir().join(format!("lpu-{}-strawberry.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE playlists (name TEXT);
+                CREATE TABLE songs (title TEXT, artist TEXT, album TEXT, url TEXT,
+                    musicbrainz_recording_id TEXT);
+                CREATE TABLE playlist_items (playlist INTEGER, collection_id INTEGER, title TEXT,
+                    artist TEXT, album TEXT, url TEXT, musicbrainz_recording_id TEXT);
+                INSERT INTO playlists (ROWID, name) VALUES (1, 'Other'), (2, 'Favourites');
+                INSERT INTO songs (ROWID, title, artist, album, url, musicbrainz_recording_id)
+                    VALUES (7, 'Perfect', 'Ed Sheeran', 'Divide',
+                    'file:///music/Ed%20Sheeran/Perfect.flac',
+                    '36855a5c-abcb-4740-9154-361af8c11ee1');
+                INSERT INTO playlist_items VALUES (2, 7, '', '', '', '', '');
+                INSERT INTO playlist_items VALUES (2, -1, 'So What', 'Miles Davis', '',
+                    'file:///music/So%20What.flac', '');
+                INSERT INTO playlist_items VALUES (1, -1, 'Elsewhere', 'Someone', '', '', '');",
+            )
+            .unwrap();
+        let tracks = read_strawberry_playlist(&path, "Favourites").unwrap();
+        assert_eq!(
+            tracks,
+            vec![
+                PlayerTrack {
+                    location: PathBuf::from("/music/Ed Sheeran/Perfect.flac"),
+                    artist: Some("Ed Sheeran".to_string()),
+                    title: Some("Perfect".to_string()),
+                    album: Some("Divide".to_string()),
+                    recording_mbid: Some(
+                        Uuid::from_str("36855a5c-abcb-4740-9154-361af8c11ee1").unwrap()
+                    ),
+                },
+                PlayerTrack {
+                    location: PathBuf::from("/music/So What.flac"),
+                    artist: Some("Miles Davis".to_string()),
+                    title: Some("So What".to_string()),
+                    album: None,
+                    recording_mbid: None,
+                },
+            ]
+        );
+        assert!(read_strawberry_playlist(&path, "Missing").is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_itunes_library() {
+        let path = std::env::temp_dir().join(format!("lpu-{}-Library.xml", std::process::id()));
+        fs::write(
+            &path,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Major Version</key><integer>1</integer>
+    <key>Tracks</key>
+    <dict>
+        <key>101</key>
+        <dict>
+            <key>Track ID</key><integer>101</integer>
+            <key>Name</key><string>Perfect</string>
+            <key>Location</key><string>file://localhost/Users/me/Music/Ed%20Sheeran/Perfect.m4a</string>
+        </dict>
+        <key>102</key>
+        <dict>
+            <key>Track ID</key><integer>102</integer>
+            <key>Location</key><string>file:///Users/me/Music/So%20What.m4a</string>
+        </dict>
+        <key>103</key>
+        <dict>
+            <key>Track ID</key><integer>103</integer>
+            <key>Location</key><string>http://radio.example/stream</string>
+        </dict>
+    </dict>
+    <key>Playlists</key>
+    <array>
+        <dict>
+            <key>Name</key><string>Library</string>
+            <key>Master</key><true/>
+            <key>Playlist Items</key>
+            <array><dict><key>Track ID</key><integer>101</integer></dict></array>
+        </dict>
+        <dict>
+            <key>Name</key><string>Road Trip</string>
+            <key>Playlist Items</key>
+            <array>
+                <dict><key>Track ID</key><integer>102</integer></dict>
+                <dict><key>Track ID</key><integer>103</integer></dict>
+                <dict><key>Track ID</key><integer>101</integer></dict>
+            </array>
+        </dict>
+    </array>
+</dict>
+</plist>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            read_playlist_library(&path).unwrap(),
+            Some(vec![PlayerPlaylist {
+                name: "Road Trip".to_string(),
+                tracks: vec![
+                    PlayerTrack::from_location(PathBuf::from("/Users/me/Music/So What.m4a")),
+                    PlayerTrack::from_location(PathBuf::from(
+                        "/Users/me/Music/Ed Sheeran/Perfect.m4a"
+                    )),
+                ],
+            }])
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_rhythmbox_playlists() {
+        let path = std::env::temp_dir().join(format!("lpu-{}-playlists.xml", std::process::id()));
+        fs::write(
+            &path,
+            r#"<?xml version="1.0"?>
+<rhythmdb-playlists>
+  <playlist name="Favourites" show-browser="true" type="static">
+    <location>file:///music/Ed%20Sheeran/Perfect.flac</location>
+    <location>file:///music/So%20What.flac</location>
+  </playlist>
+  <playlist name="Recently Added" type="automatic" limit-count="0">
+    <conjunction><subquery/></conjunction>
+  </playlist>
+  <playlist name="Play Queue" type="queue">
+    <location>file:///music/Queued.flac</location>
+  </playlist>
+</rhythmdb-playlists>"#,
+        )
+        .unwrap();
+        let playlists = read_playlist_library(&path).unwrap().unwrap();
+        assert_eq!(playlists.len(), 2);
+        assert_eq!(
+            playlists[0],
+            PlayerPlaylist {
+                name: "Favourites".to_string(),
+                tracks: vec![
+                    PlayerTrack::from_location(PathBuf::from("/music/Ed Sheeran/Perfect.flac")),
+                    PlayerTrack::from_location(PathBuf::from("/music/So What.flac")),
+                ],
+            }
+        );
+        assert!(playlists[1].tracks.is_empty());
+        assert_eq!(
+            read_playlist_library(Path::new("./tests/test_playlist_1.m3u")).unwrap(),
+            None
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
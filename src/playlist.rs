@@ -1,14 +1,30 @@
+use crate::journal::Journal;
 use crate::listenbrainz_client::ListenbrainzClient;
+use crate::paginator::{fetch_all_pages, Page};
+use crate::{resolve_user_name, visibility, PlaylistCommand, PruneKeep};
 use anyhow::{anyhow, Error, Result};
-use log::debug;
+use chrono::{DateTime, FixedOffset};
+use indicatif::ProgressBar;
+use inquire::Confirm;
+use log::{debug, error, info};
+use regex::Regex;
 use reqwest::StatusCode;
 use serde::{Deserialize, Serialize, Serializer};
 use serde_json::{Map, Value};
 use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process::exit;
 use std::str::FromStr;
 use url::Url;
 use uuid::Uuid;
 
+const JSPF_PLAYLIST_EXTENSION: &str = "https://musicbrainz.org/doc/jspf#playlist";
+const PLAYLISTS_PER_PAGE: usize = 100;
+/// The most recordings ListenBrainz accepts in a single create or add request. This is assumed
+/// rather than read from the server, which doesn't report it.
+pub const MAX_TRACKS_PER_REQUEST: usize = 100;
+
 #[derive(Deserialize)]
 pub struct PlaylistSubmissionResponse {
     pub playlist_mbid: Uuid,
@@ -16,8 +32,33 @@ pub struct PlaylistSubmissionResponse {
 
 struct SubmissionPlaylist<'a> {
     name: String,
-    song_mbids: &'a [Uuid],
+    tracks: &'a [PlaylistTrack],
     public: bool,
+    metadata: &'a PlaylistMetadata,
+}
+
+/// Changes to the details of an existing playlist, leaving out anything that shouldn't change
+#[derive(Debug, Default)]
+pub struct PlaylistEdit {
+    pub title: Option<String>,
+    pub public: Option<bool>,
+    pub metadata: PlaylistMetadata,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlaylistTrack {
+    pub mbid: Uuid,
+    pub annotation: Option<String>,
+}
+
+/// Optional JSPF details of a playlist, which can also be read from a JSON sidecar file
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+pub struct PlaylistMetadata {
+    pub title: Option<String>,
+    pub annotation: Option<String>,
+    #[serde(default)]
+    pub collaborators: Vec<String>,
+    pub copied_from: Option<Uuid>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -29,11 +70,19 @@ struct ValidationResponse {
 pub struct SimpleExistingPlaylistResponse {
     pub title: String,
     pub identifier: Uuid,
+    pub creator: String,
+    pub created: Option<DateTime<FixedOffset>>,
+    pub last_modified: Option<DateTime<FixedOffset>>,
+    pub public: bool,
 }
 
 pub struct FullExistingPlaylistResponse {
     pub identifier: Uuid,
     pub number_of_tracks: usize,
+    pub title: String,
+    pub public: bool,
+    pub annotation: Option<String>,
+    pub collaborators: Vec<String>,
 }
 
 impl SimpleExistingPlaylistResponse {
@@ -43,8 +92,10 @@ impl SimpleExistingPlaylistResponse {
 
         if let Value::Array(individual_playlist) = &data["playlists"] {
             for playlist_data in individual_playlist {
-                let identifier = playlist_data["playlist"]["identifier"].as_str().unwrap();
-                let title = playlist_data["playlist"]["title"].as_str().unwrap();
+                let playlist = &playlist_data["playlist"];
+                let identifier = playlist["identifier"].as_str().unwrap();
+                let title = playlist["title"].as_str().unwrap();
+                let extension = &playlist["extension"][JSPF_PLAYLIST_EXTENSION];
                 playlists.push(SimpleExistingPlaylistResponse {
                     title: title.to_string(),
                     identifier: Uuid::from_str(
@@ -55,19 +106,44 @@ impl SimpleExistingPlaylistResponse {
                             .unwrap(),
                     )
                     .expect("Could not convert to valid UUID"),
+                    creator: playlist["creator"].as_str().unwrap_or_default().to_string(),
+                    created: parse_playlist_date(&playlist["date"]),
+                    last_modified: parse_playlist_date(&extension["last_modified_at"]),
+                    public: extension["public"].as_bool().unwrap_or(false),
                 });
             }
         }
         Ok(playlists)
     }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{} ({}) by {}, last modified {}",
+            self.title,
+            self.identifier,
+            self.creator,
+            self.last_modified
+                .or(self.created)
+                .map(|d| d.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_else(|| "at an unknown time".to_string())
+        )
+    }
+}
+
+fn parse_playlist_date(value: &Value) -> Option<DateTime<FixedOffset>> {
+    value
+        .as_str()
+        .and_then(|d| DateTime::parse_from_rfc3339(d).ok())
 }
 
 impl FullExistingPlaylistResponse {
     pub fn from_json(json: &str) -> Result<Self> {
         let data: Value = serde_json::from_str(json)?;
 
-        let identifier = data["playlist"]["identifier"].as_str().unwrap();
-        let number_of_tracks = data["playlist"]["track"].as_array().unwrap().len();
+        let playlist = &data["playlist"];
+        let identifier = playlist["identifier"].as_str().unwrap();
+        let number_of_tracks = playlist["track"].as_array().unwrap().len();
+        let extension = &playlist["extension"][JSPF_PLAYLIST_EXTENSION];
         Ok(FullExistingPlaylistResponse {
             identifier: Uuid::from_str(
                 identifier
@@ -78,15 +154,69 @@ impl FullExistingPlaylistResponse {
             )
             .expect("Could not convert to valid UUID"),
             number_of_tracks,
+            title: playlist["title"].as_str().unwrap_or_default().to_string(),
+            public: extension["public"].as_bool().unwrap_or(false),
+            annotation: playlist["annotation"].as_str().map(|a| a.to_string()),
+            collaborators: extension["collaborators"]
+                .as_array()
+                .map(|c| {
+                    c.iter()
+                        .filter_map(|c| c.as_str())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
         })
     }
     pub async fn convert_simple_playlist_response_to_full(
-        listenbrainz_client: &mut ListenbrainzClient,
+        listenbrainz_client: &ListenbrainzClient,
         simple_playlist: &SimpleExistingPlaylistResponse,
     ) -> Result<Self> {
+        // Listings are sent without their tracks, so counting them needs the whole playlist
         get_full_specific_playlist(listenbrainz_client, &simple_playlist.identifier).await
     }
 }
+impl PlaylistMetadata {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Fills in anything not set here from another, lower priority, source
+    pub fn or(self, other: PlaylistMetadata) -> Self {
+        PlaylistMetadata {
+            title: self.title.or(other.title),
+            annotation: self.annotation.or(other.annotation),
+            collaborators: if self.collaborators.is_empty() {
+                other.collaborators
+            } else {
+                self.collaborators
+            },
+            copied_from: self.copied_from.or(other.copied_from),
+        }
+    }
+
+    fn insert_into(
+        &self,
+        playlist_map: &mut Map<String, Value>,
+        musicbrainz_map: &mut Map<String, Value>,
+    ) {
+        if let Some(annotation) = &self.annotation {
+            playlist_map.insert("annotation".to_string(), Value::String(annotation.clone()));
+        }
+        if !self.collaborators.is_empty() {
+            musicbrainz_map.insert(
+                "collaborators".to_string(),
+                Value::Array(
+                    self.collaborators
+                        .iter()
+                        .map(|c| Value::String(c.clone()))
+                        .collect(),
+                ),
+            );
+        }
+    }
+}
+
 impl Serialize for SubmissionPlaylist<'_> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -98,13 +228,16 @@ impl Serialize for SubmissionPlaylist<'_> {
         playlist_map.insert("title".to_string(), Value::String(self.name.clone()));
 
         let tracks: Vec<Value> = self
-            .song_mbids
+            .tracks
             .iter()
-            .map(|mbid| {
+            .map(|track| {
                 let mut song_map = Map::new();
-                let mut mbid_url = mbid.clone().to_string();
+                let mut mbid_url = track.mbid.to_string();
                 mbid_url.insert_str(0, "https://musicbrainz.org/recording/");
                 song_map.insert("identifier".to_string(), Value::String(mbid_url));
+                if let Some(annotation) = &track.annotation {
+                    song_map.insert("annotation".to_string(), Value::String(annotation.clone()));
+                }
                 Value::Object(song_map)
             })
             .collect();
@@ -114,8 +247,16 @@ impl Serialize for SubmissionPlaylist<'_> {
         let mut musicbrainz_map = Map::new();
 
         musicbrainz_map.insert("public".to_string(), Value::Bool(self.public));
+        self.metadata
+            .insert_into(&mut playlist_map, &mut musicbrainz_map);
+        if let Some(copied_from) = self.metadata.copied_from {
+            musicbrainz_map.insert(
+                "copied_from".to_string(),
+                Value::String(format!("https://listenbrainz.org/playlist/{copied_from}")),
+            );
+        }
         extension_map.insert(
-            "https://musicbrainz.org/doc/jspf#playlist".to_string(),
+            JSPF_PLAYLIST_EXTENSION.to_string(),
             Value::Object(musicbrainz_map),
         );
         playlist_map.insert("extension".to_string(), Value::Object(extension_map));
@@ -126,16 +267,81 @@ impl Serialize for SubmissionPlaylist<'_> {
     }
 }
 
+impl PlaylistEdit {
+    /// The changes that make an existing playlist match what was asked for, leaving out anything
+    /// it already has. Titles are only changed when one is given explicitly.
+    pub fn from_differences(
+        existing: &FullExistingPlaylistResponse,
+        title: Option<String>,
+        public: Option<bool>,
+        metadata: &PlaylistMetadata,
+    ) -> Self {
+        PlaylistEdit {
+            title: title.filter(|t| *t != existing.title),
+            public: public.filter(|p| *p != existing.public),
+            metadata: PlaylistMetadata {
+                annotation: metadata
+                    .annotation
+                    .clone()
+                    .filter(|a| existing.annotation.as_ref() != Some(a)),
+                collaborators: if metadata.collaborators == existing.collaborators {
+                    Vec::new()
+                } else {
+                    metadata.collaborators.clone()
+                },
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Whether there is anything to change on an existing playlist
+    pub fn has_changes(&self) -> bool {
+        self.title.is_some()
+            || self.public.is_some()
+            || self.metadata.annotation.is_some()
+            || !self.metadata.collaborators.is_empty()
+    }
+}
+
+impl Serialize for PlaylistEdit {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut playlist_map = Map::new();
+        let mut musicbrainz_map = Map::new();
+        if let Some(title) = &self.title {
+            playlist_map.insert("title".to_string(), Value::String(title.clone()));
+        }
+        if let Some(public) = self.public {
+            musicbrainz_map.insert("public".to_string(), Value::Bool(public));
+        }
+        self.metadata
+            .insert_into(&mut playlist_map, &mut musicbrainz_map);
+        if !musicbrainz_map.is_empty() {
+            let mut extension_map = Map::new();
+            extension_map.insert(
+                JSPF_PLAYLIST_EXTENSION.to_string(),
+                Value::Object(musicbrainz_map),
+            );
+            playlist_map.insert("extension".to_string(), Value::Object(extension_map));
+        }
+        HashMap::from([("playlist", Value::Object(playlist_map))]).serialize(serializer)
+    }
+}
+
 pub async fn submit_playlist(
-    listenbrainz_client: &mut ListenbrainzClient,
-    mbid_vec: &Vec<Uuid>,
+    listenbrainz_client: &ListenbrainzClient,
+    tracks: &[PlaylistTrack],
     playlist_name: String,
     public_playlist: bool,
+    metadata: &PlaylistMetadata,
 ) -> Result<PlaylistSubmissionResponse> {
     let data = SubmissionPlaylist {
         name: playlist_name,
         public: public_playlist,
-        song_mbids: mbid_vec,
+        tracks,
+        metadata,
     };
     let response = listenbrainz_client
         .take_request_builder(
@@ -149,7 +355,7 @@ pub async fn submit_playlist(
     Ok(playlist_id)
 }
 
-pub async fn get_current_user(listenbrainz_client: &mut ListenbrainzClient) -> Result<String> {
+pub async fn get_current_user(listenbrainz_client: &ListenbrainzClient) -> Result<String> {
     let response = listenbrainz_client
         .take_request_builder(
             listenbrainz_client
@@ -165,24 +371,52 @@ pub async fn get_current_user(listenbrainz_client: &mut ListenbrainzClient) -> R
     }
 }
 
+/// Gets the playlists the user created as well as those they are a collaborator on
 pub async fn get_current_playlists(
-    listenbrainz_client: &mut ListenbrainzClient,
+    listenbrainz_client: &ListenbrainzClient,
     user_name: &String,
 ) -> Result<Vec<SimpleExistingPlaylistResponse>> {
-    let url = Url::parse_with_params(
+    let mut playlists = get_paginated_playlists(
+        listenbrainz_client,
         &format!("https://api.listenbrainz.org/1/user/{user_name}/playlists"),
-        [("count", u32::MAX.to_string())],
-    )?;
-    let response = listenbrainz_client
-        .take_request_builder(listenbrainz_client.request_client.get(url))
-        .await;
-    let response_text = response?.text().await?;
-    let playlist_objects = SimpleExistingPlaylistResponse::from_json(response_text.as_str())?;
-    Ok(playlist_objects)
+    )
+    .await?;
+    let collaborative_playlists = get_paginated_playlists(
+        listenbrainz_client,
+        &format!("https://api.listenbrainz.org/1/user/{user_name}/playlists/collaborator"),
+    )
+    .await?;
+    for playlist in collaborative_playlists {
+        if !playlists
+            .iter()
+            .any(|p| p.identifier == playlist.identifier)
+        {
+            playlists.push(playlist);
+        }
+    }
+    Ok(playlists)
 }
 
-async fn get_full_specific_playlist(
-    listenbrainz_client: &mut ListenbrainzClient,
+async fn get_paginated_playlists(
+    listenbrainz_client: &ListenbrainzClient,
+    base_url: &str,
+) -> Result<Vec<SimpleExistingPlaylistResponse>> {
+    fetch_all_pages(listenbrainz_client, base_url, PLAYLISTS_PER_PAGE, |json| {
+        let playlists = SimpleExistingPlaylistResponse::from_json(json)?;
+        let total_count = serde_json::from_str::<Value>(json)?["playlist_count"]
+            .as_u64()
+            .map(|c| c as usize);
+        Ok(Page {
+            count: playlists.len(),
+            items: playlists,
+            total_count,
+        })
+    })
+    .await
+}
+
+pub async fn get_full_specific_playlist(
+    listenbrainz_client: &ListenbrainzClient,
     playlist_id: &Uuid,
 ) -> Result<FullExistingPlaylistResponse> {
     let url = Url::parse(&format!(
@@ -197,7 +431,7 @@ async fn get_full_specific_playlist(
 }
 
 pub async fn delete_items_from_playlist(
-    listenbrainz_client: &mut ListenbrainzClient,
+    listenbrainz_client: &ListenbrainzClient,
     playlist_id: &Uuid,
     start_index: usize,
     count_to_remove: usize,
@@ -214,30 +448,73 @@ pub async fn delete_items_from_playlist(
     match_error_from_playlist_change(response)
 }
 
+pub async fn edit_playlist(
+    listenbrainz_client: &ListenbrainzClient,
+    playlist_id: &Uuid,
+    changes: &PlaylistEdit,
+) -> Result<()> {
+    let url = Url::parse(&format!(
+        "https://api.listenbrainz.org/1/playlist/edit/{playlist_id}",
+    ))?;
+    debug!("Editing playlist with URL '{url}'");
+    let response = listenbrainz_client
+        .take_request_builder(listenbrainz_client.request_client.post(url).json(changes))
+        .await;
+    let response = response?.status();
+    match_error_from_playlist_change(response)
+}
+
+pub async fn delete_playlist(
+    listenbrainz_client: &ListenbrainzClient,
+    playlist_id: &Uuid,
+) -> Result<()> {
+    let url = Url::parse(&format!(
+        "https://api.listenbrainz.org/1/playlist/{playlist_id}/delete",
+    ))?;
+    debug!("Deleting playlist with URL '{url}'");
+    let response = listenbrainz_client
+        .take_request_builder(listenbrainz_client.request_client.post(url))
+        .await;
+    let response = response?.status();
+    match_error_from_playlist_change(response)
+}
+
 pub async fn mass_add_to_playlist(
-    listenbrainz_client: &mut ListenbrainzClient,
+    listenbrainz_client: &ListenbrainzClient,
     playlist_id: &Uuid,
-    track_mbids: &[Uuid],
+    tracks: &[PlaylistTrack],
+    chunk_size: usize,
+    journal: &mut Journal,
+    progress_bar: &ProgressBar,
 ) -> Result<()> {
-    for chunk in track_mbids.chunks(100) {
+    let already_added = journal.tracks_added.min(tracks.len());
+    if already_added > 0 {
+        debug!("Skipping {already_added} tracks added in a previous run");
+    }
+    progress_bar.set_position(already_added as u64);
+    for chunk in tracks[already_added..].chunks(chunk_size) {
         add_items_to_playlist(listenbrainz_client, playlist_id, chunk).await?;
+        journal.tracks_added += chunk.len();
+        journal.checkpoint();
+        progress_bar.inc(chunk.len() as u64);
     }
     Ok(())
 }
 
 pub async fn add_items_to_playlist(
-    listenbrainz_client: &mut ListenbrainzClient,
+    listenbrainz_client: &ListenbrainzClient,
     playlist_id: &Uuid,
-    track_mbids: &[Uuid],
+    tracks: &[PlaylistTrack],
 ) -> Result<()> {
     let url = Url::parse(&format!(
-        "https://api.listenbrainz.org/1/playlist/{playlist_id}/item/add/0",
+        "https://api.listenbrainz.org/1/playlist/{playlist_id}/item/add",
     ))?;
     debug!("Inserting tracks to playlist with URL '{}'", &url);
     let data = SubmissionPlaylist {
         name: "addition".to_string(),
         public: false,
-        song_mbids: track_mbids,
+        tracks,
+        metadata: &PlaylistMetadata::default(),
     };
     let response = listenbrainz_client
         .take_request_builder(listenbrainz_client.request_client.post(url).json(&data))
@@ -256,17 +533,243 @@ fn match_error_from_playlist_change(response: StatusCode) -> Result<(), Error> {
     }
 }
 
+/// Manages existing playlists of the user's
+pub async fn run_playlist_command(
+    client: &ListenbrainzClient,
+    command: &PlaylistCommand,
+    no_confirm: bool,
+) {
+    match command {
+        PlaylistCommand::Edit {
+            playlist_id,
+            title,
+            description,
+            public,
+            private,
+        } => {
+            let changes = PlaylistEdit {
+                title: title.clone(),
+                public: visibility(*public, *private),
+                metadata: PlaylistMetadata {
+                    annotation: description.clone(),
+                    ..Default::default()
+                },
+            };
+            if !changes.has_changes() {
+                error!("Nothing to change on the playlist");
+                exit(1)
+            }
+            match edit_playlist(client, playlist_id, &changes).await {
+                Ok(()) => info!("Updated playlist with ID {}", playlist_id),
+                Err(e) => {
+                    error!("Could not update playlist: {}", e);
+                    exit(1)
+                }
+            }
+        }
+        PlaylistCommand::Delete {
+            playlist_ids,
+            title_pattern,
+        } => {
+            let own_playlists = get_own_playlists(client).await;
+            let to_delete: Vec<(Uuid, String)> = match title_pattern {
+                Some(pattern) => {
+                    let pattern = Regex::new(pattern).unwrap_or_else(|e| {
+                        error!("Invalid title pattern: {}", e);
+                        exit(1)
+                    });
+                    own_playlists
+                        .iter()
+                        .filter(|p| pattern.is_match(&p.title))
+                        .map(|p| (p.identifier, p.describe()))
+                        .collect()
+                }
+                None => playlist_ids
+                    .iter()
+                    .map(
+                        |id| match own_playlists.iter().find(|p| p.identifier == *id) {
+                            Some(p) => (*id, p.describe()),
+                            None => (*id, id.to_string()),
+                        },
+                    )
+                    .collect(),
+            };
+            delete_playlists(client, &to_delete, no_confirm).await;
+        }
+        PlaylistCommand::PruneDuplicates { keep, dry_run } => {
+            let own_playlists = get_own_playlists(client).await;
+            let mut titles: HashMap<&str, Vec<&SimpleExistingPlaylistResponse>> = HashMap::new();
+            for p in &own_playlists {
+                titles.entry(p.title.as_str()).or_default().push(p);
+            }
+            let mut to_delete = Vec::new();
+            for group in titles.into_values().filter(|g| g.len() > 1) {
+                let mut counted_group = Vec::new();
+                for p in group {
+                    let full =
+                        FullExistingPlaylistResponse::convert_simple_playlist_response_to_full(
+                            client, p,
+                        )
+                        .await
+                        .unwrap_or_else(|e| {
+                            error!(
+                                "Could not count the tracks of playlist {}: {}",
+                                p.identifier, e
+                            );
+                            exit(1)
+                        });
+                    counted_group.push((p, full.number_of_tracks));
+                }
+                let (kept, pruned) = split_duplicates_to_prune(counted_group, *keep);
+                info!("Keeping {} ({} tracks)", kept.0.describe(), kept.1);
+                for (p, number_of_tracks) in pruned {
+                    info!(
+                        "  would delete {} ({} tracks)",
+                        p.describe(),
+                        number_of_tracks
+                    );
+                    to_delete.push((p.identifier, p.describe()));
+                }
+            }
+            if to_delete.is_empty() {
+                info!("No duplicate playlists found");
+            } else if *dry_run {
+                info!("Dry run, {} playlists were not deleted", to_delete.len());
+            } else {
+                delete_playlists(client, &to_delete, no_confirm).await;
+            }
+        }
+    }
+}
+
+pub async fn get_own_playlists(client: &ListenbrainzClient) -> Vec<SimpleExistingPlaylistResponse> {
+    let user_name = resolve_user_name(client).await;
+    match get_current_playlists(client, &user_name).await {
+        // Playlists someone else created can't be deleted, even by a collaborator
+        Ok(playlists) => playlists
+            .into_iter()
+            .filter(|p| p.creator == user_name)
+            .collect(),
+        Err(e) => {
+            error!("Could not retrieve existing playlists: {}", e);
+            exit(1)
+        }
+    }
+}
+
+/// Splits same-titled playlists, each with its number of tracks, into the one to keep and the rest
+pub fn split_duplicates_to_prune(
+    mut playlists: Vec<(&SimpleExistingPlaylistResponse, usize)>,
+    keep: PruneKeep,
+) -> (
+    (&SimpleExistingPlaylistResponse, usize),
+    Vec<(&SimpleExistingPlaylistResponse, usize)>,
+) {
+    let newest = |p: &SimpleExistingPlaylistResponse| p.last_modified.or(p.created);
+    let kept_index = match keep {
+        PruneKeep::Newest => playlists
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, (p, n))| (newest(p), *n)),
+        PruneKeep::MostTracks => playlists
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, (p, n))| (*n, newest(p))),
+    }
+    .map(|(i, _)| i)
+    .expect("Duplicate playlists should not be empty");
+    let kept = playlists.remove(kept_index);
+    (kept, playlists)
+}
+
+pub async fn delete_playlists(
+    client: &ListenbrainzClient,
+    playlists: &[(Uuid, String)],
+    no_confirm: bool,
+) {
+    if playlists.is_empty() {
+        info!("No playlists to delete");
+        return;
+    }
+    info!("These playlists will be deleted:");
+    for (_, description) in playlists {
+        info!("  {}", description);
+    }
+    if !no_confirm {
+        match Confirm::new(&format!("Delete {} playlists?", playlists.len()))
+            .with_default(false)
+            .prompt()
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                info!("Aborting");
+                exit(1)
+            }
+            Err(e) => {
+                error!("Error with questionaire: {}", e);
+                exit(1)
+            }
+        }
+    }
+    for (playlist_id, _) in playlists {
+        match delete_playlist(client, playlist_id).await {
+            Ok(()) => info!("Deleted playlist with ID {}", playlist_id),
+            Err(e) => error!("Could not delete playlist with ID {}: {}", playlist_id, e),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use serde_test::{assert_ser_tokens, Token};
 
+    fn track(mbid: &str) -> PlaylistTrack {
+        PlaylistTrack {
+            mbid: Uuid::from_str(mbid).unwrap(),
+            annotation: None,
+        }
+    }
+
+    #[test]
+    fn test_simple_playlist_from_json() {
+        let json = r#"{
+            "count": 1,
+            "offset": 0,
+            "playlist_count": 1,
+            "playlists": [{"playlist": {
+                "creator": "Serene-Arc",
+                "date": "2024-01-14T10:21:09.431000+00:00",
+                "identifier": "https://listenbrainz.org/playlist/36855a5c-abcb-4740-9154-361af8c11ee1",
+                "title": "Example",
+                "track": [],
+                "extension": {"https://musicbrainz.org/doc/jspf#playlist": {
+                    "creator": "Serene-Arc",
+                    "last_modified_at": "2024-02-01T08:00:00.000000+00:00",
+                    "public": true
+                }}
+            }}]
+        }"#;
+        let result = SimpleExistingPlaylistResponse::from_json(json).unwrap();
+        assert_eq!(result.len(), 1);
+        let playlist = &result[0];
+        assert_eq!(playlist.title, "Example");
+        assert_eq!(
+            playlist.identifier,
+            Uuid::from_str("36855a5c-abcb-4740-9154-361af8c11ee1").unwrap()
+        );
+        assert_eq!(playlist.creator, "Serene-Arc");
+        assert!(playlist.public);
+        assert!(playlist.created.unwrap() < playlist.last_modified.unwrap());
+    }
+
     #[test]
     fn test_serialise_playlist_no_tracks() {
         let test = SubmissionPlaylist {
             name: "Example".to_string(),
-            song_mbids: &Vec::new(),
+            tracks: &Vec::new(),
             public: false,
+            metadata: &PlaylistMetadata::default(),
         };
         assert_ser_tokens(
             &test,
@@ -297,8 +800,9 @@ mod test {
     fn test_serialise_playlist_one_track() {
         let test = SubmissionPlaylist {
             name: "Example".to_string(),
-            song_mbids: &vec![Uuid::from_str("36855a5c-abcb-4740-9154-361af8c11ee1").unwrap()],
+            tracks: &[track("36855a5c-abcb-4740-9154-361af8c11ee1")],
             public: false,
+            metadata: &PlaylistMetadata::default(),
         };
         assert_ser_tokens(
             &test,
@@ -335,11 +839,12 @@ mod test {
     fn test_serialise_playlist_two_tracks() {
         let test = SubmissionPlaylist {
             name: "Example".to_string(),
-            song_mbids: &vec![
-                Uuid::from_str("36855a5c-abcb-4740-9154-361af8c11ee1").unwrap(),
-                Uuid::from_str("00066722-b23a-48e5-82e4-0470c82a2705").unwrap(),
+            tracks: &[
+                track("36855a5c-abcb-4740-9154-361af8c11ee1"),
+                track("00066722-b23a-48e5-82e4-0470c82a2705"),
             ],
             public: false,
+            metadata: &PlaylistMetadata::default(),
         };
         assert_ser_tokens(
             &test,
@@ -377,4 +882,141 @@ mod test {
             ],
         );
     }
+
+    #[test]
+    fn test_serialise_playlist_with_metadata() {
+        let test = SubmissionPlaylist {
+            name: "Example".to_string(),
+            tracks: &[PlaylistTrack {
+                annotation: Some("/music/song.flac".to_string()),
+                ..track("36855a5c-abcb-4740-9154-361af8c11ee1")
+            }],
+            public: true,
+            metadata: &PlaylistMetadata {
+                title: None,
+                annotation: Some("Songs for the road".to_string()),
+                collaborators: vec!["friend".to_string()],
+                copied_from: Some(Uuid::from_str("00066722-b23a-48e5-82e4-0470c82a2705").unwrap()),
+            },
+        };
+        let json = serde_json::to_value(&test).unwrap();
+        let playlist = &json["playlist"];
+        assert_eq!(playlist["annotation"], "Songs for the road");
+        assert_eq!(playlist["track"][0]["annotation"], "/music/song.flac");
+        let extension = &playlist["extension"][JSPF_PLAYLIST_EXTENSION];
+        assert_eq!(extension["public"], true);
+        assert_eq!(extension["collaborators"][0], "friend");
+        assert_eq!(
+            extension["copied_from"],
+            "https://listenbrainz.org/playlist/00066722-b23a-48e5-82e4-0470c82a2705"
+        );
+    }
+
+    #[test]
+    fn test_serialise_playlist_edit_only_changed_fields() {
+        let changes = PlaylistEdit {
+            metadata: PlaylistMetadata {
+                annotation: Some("Songs for the road".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(changes.has_changes());
+        let json = serde_json::to_value(&changes).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"playlist": {"annotation": "Songs for the road"}})
+        );
+        assert!(!PlaylistEdit::default().has_changes());
+    }
+
+    #[test]
+    fn test_serialise_playlist_edit_title_and_visibility() {
+        let changes = PlaylistEdit {
+            title: Some("Renamed".to_string()),
+            public: Some(false),
+            ..Default::default()
+        };
+        let json = serde_json::to_value(&changes).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"playlist": {
+                "title": "Renamed",
+                "extension": {JSPF_PLAYLIST_EXTENSION: {"public": false}},
+            }})
+        );
+    }
+
+    #[test]
+    fn test_playlist_edit_only_has_differences() {
+        let existing = FullExistingPlaylistResponse::from_json(
+            r#"{"playlist": {
+                "identifier": "https://listenbrainz.org/playlist/36855a5c-abcb-4740-9154-361af8c11ee1",
+                "title": "Road Trip",
+                "annotation": "Songs for the road",
+                "track": [{}, {}],
+                "extension": {"https://musicbrainz.org/doc/jspf#playlist": {
+                    "public": true,
+                    "collaborators": ["friend"]
+                }}
+            }}"#,
+        )
+        .unwrap();
+        assert_eq!(existing.number_of_tracks, 2);
+        let unchanged = PlaylistMetadata {
+            title: Some("From the playlist header".to_string()),
+            annotation: Some("Songs for the road".to_string()),
+            collaborators: vec!["friend".to_string()],
+            copied_from: None,
+        };
+        let changes = PlaylistEdit::from_differences(&existing, None, Some(true), &unchanged);
+        assert!(!changes.has_changes());
+        let changes = PlaylistEdit::from_differences(
+            &existing,
+            Some("Renamed".to_string()),
+            Some(false),
+            &PlaylistMetadata::default(),
+        );
+        assert_eq!(changes.title.as_deref(), Some("Renamed"));
+        assert_eq!(changes.public, Some(false));
+        assert!(changes.metadata.annotation.is_none());
+    }
+
+    #[test]
+    fn test_playlist_metadata_priority() {
+        let from_flags = PlaylistMetadata {
+            annotation: Some("From flags".to_string()),
+            ..Default::default()
+        };
+        let from_file = PlaylistMetadata {
+            title: Some("From file".to_string()),
+            annotation: Some("From file".to_string()),
+            collaborators: vec!["friend".to_string()],
+            copied_from: None,
+        };
+        let merged = from_flags.or(from_file);
+        assert_eq!(merged.title.as_deref(), Some("From file"));
+        assert_eq!(merged.annotation.as_deref(), Some("From flags"));
+        assert_eq!(merged.collaborators, vec!["friend".to_string()]);
+    }
+
+    #[test]
+    fn test_split_duplicates_to_prune() {
+        let json = r#"{"playlists": [
+            {"playlist": {"identifier": "https://listenbrainz.org/playlist/36855a5c-abcb-4740-9154-361af8c11ee1",
+                "title": "Example", "creator": "user", "date": "2023-01-01T00:00:00+00:00"}},
+            {"playlist": {"identifier": "https://listenbrainz.org/playlist/00066722-b23a-48e5-82e4-0470c82a2705",
+                "title": "Example", "creator": "user", "date": "2024-01-01T00:00:00+00:00"}}
+        ]}"#;
+        let playlists = SimpleExistingPlaylistResponse::from_json(json).unwrap();
+        let counted = vec![(&playlists[0], 20), (&playlists[1], 5)];
+
+        let (kept, pruned) = split_duplicates_to_prune(counted.clone(), PruneKeep::Newest);
+        assert_eq!(kept.0.identifier, playlists[1].identifier);
+        assert_eq!(pruned.len(), 1);
+        assert_eq!(pruned[0].0.identifier, playlists[0].identifier);
+
+        let (kept, _) = split_duplicates_to_prune(counted, PruneKeep::MostTracks);
+        assert_eq!(kept.0.identifier, playlists[0].identifier);
+    }
 }
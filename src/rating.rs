@@ -0,0 +1,516 @@
+use crate::feedback::{
+    current_feedback, get_all_feedback, is_feedback_conflict, resolve_feedback_conflicts,
+};
+use crate::listenbrainz_client::ListenbrainzClient;
+use crate::players::PlayerTrack;
+use crate::upload::{load_and_resolve_songs, load_file_paths, UploadContext};
+use crate::{
+    audio_data, feedback, make_progress_bar, resolve_user_name, Args, DedupePolicy, Feedback,
+};
+use anyhow::{anyhow, Result};
+use config::Config;
+use lofty::config::{ParseOptions, WriteOptions};
+use lofty::file::{AudioFile, FileType, TaggedFileExt};
+use lofty::id3::v2::{Frame, Id3v2Tag, PopularimeterFrame};
+use lofty::mpeg::MpegFile;
+use lofty::tag::{ItemKey, Tag, TagExt, TagType};
+use log::{error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use uuid::Uuid;
+
+// The FMPS specification spells this differently in ID3v2 and Vorbis comments
+const FMPS_RATING_KEYS: [&str; 2] = ["FMPS_RATING", "FMPS_Rating"];
+const DEFAULT_POPM_EMAIL: &str = "Windows Media Player 9 Series";
+pub const DEFAULT_VORBIS_RATING_SCALE: f64 = 100.0;
+
+/// Where ratings, between 0 and 1, become loves and hates
+#[derive(Debug, Clone, Copy)]
+pub struct RatingThresholds {
+    pub love: f64,
+    pub hate: f64,
+}
+
+impl Default for RatingThresholds {
+    fn default() -> Self {
+        // Five stars is a love and one star is a hate
+        RatingThresholds {
+            love: 0.9,
+            hate: 0.2,
+        }
+    }
+}
+
+impl RatingThresholds {
+    pub fn feedback_for(&self, rating: f64) -> Feedback {
+        if rating >= self.love {
+            Feedback::Love
+        } else if rating <= self.hate {
+            Feedback::Hate
+        } else {
+            Feedback::Neutral
+        }
+    }
+
+    /// A hate is written below its threshold, so rounding in tags can't push it over
+    pub fn rating_for(&self, feedback: Feedback) -> Option<f64> {
+        match feedback {
+            Feedback::Love => Some(1.0),
+            Feedback::Hate => Some(self.hate / 2.0),
+            Feedback::Neutral => None,
+        }
+    }
+}
+
+/// Reads the rating of a file from FMPS_RATING, POPM or RATING tags, scaled between 0 and 1.
+/// Vorbis `RATING` values are out of `vorbis_scale`.
+pub fn read_rating(file: &Path, vorbis_scale: f64) -> Result<Option<f64>> {
+    let tagged_file = lofty::read_from_path(file)?;
+    if let Some(tag) = tagged_file.primary_tag() {
+        for key in FMPS_RATING_KEYS {
+            if let Some(rating) = tag
+                .get_string(&ItemKey::Unknown(key.to_string()))
+                .and_then(|r| r.trim().parse::<f64>().ok())
+            {
+                return Ok(Some(rating.clamp(0.0, 1.0)));
+            }
+        }
+        // This is the RATING field in Vorbis comments, which players scale differently
+        if let Some(rating) = tag
+            .get_string(&ItemKey::Popularimeter)
+            .and_then(|r| r.trim().parse::<f64>().ok())
+            .filter(|r| *r > 0.0)
+        {
+            return Ok(Some((rating / vorbis_scale).clamp(0.0, 1.0)));
+        }
+    }
+    if tagged_file.file_type() == FileType::Mpeg {
+        // POPM frames aren't kept in the generic tag, so they need the ID3v2 tag itself
+        if let Some(id3v2_tag) = read_mpeg_file(file)?.id3v2() {
+            for frame in id3v2_tag {
+                if let Frame::Popularimeter(popularimeter) = frame {
+                    // 0 means unrated, so 1 is the lowest rating
+                    match popularimeter.rating {
+                        0 => {}
+                        1 => return Ok(Some(0.0)),
+                        r => return Ok(Some(f64::from(r) / 255.0)),
+                    }
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Writes a rating between 0 and 1, with Vorbis `RATING` values out of `vorbis_scale`
+pub fn write_rating(file: &Path, rating: f64, vorbis_scale: f64) -> Result<()> {
+    let rating = rating.clamp(0.0, 1.0);
+    let tagged_file = lofty::read_from_path(file)?;
+    match tagged_file.primary_tag_type() {
+        TagType::Id3v2 if tagged_file.file_type() == FileType::Mpeg => {
+            let mut id3v2_tag = read_mpeg_file(file)?
+                .id3v2()
+                .cloned()
+                .unwrap_or_else(Id3v2Tag::new);
+            let existing: Vec<PopularimeterFrame> = (&id3v2_tag)
+                .into_iter()
+                .filter_map(|f| match f {
+                    Frame::Popularimeter(p) => Some(p.clone()),
+                    _ => None,
+                })
+                .collect();
+            let popularimeter_rating = ((rating * 255.0).round() as u8).max(1);
+            if existing.is_empty() {
+                id3v2_tag.insert(Frame::Popularimeter(PopularimeterFrame::new(
+                    DEFAULT_POPM_EMAIL.to_string(),
+                    popularimeter_rating,
+                    0,
+                )));
+            }
+            for mut popularimeter in existing {
+                popularimeter.rating = popularimeter_rating;
+                id3v2_tag.insert(Frame::Popularimeter(popularimeter));
+            }
+            id3v2_tag.insert_user_text(FMPS_RATING_KEYS[1].to_string(), format!("{rating:.2}"));
+            id3v2_tag.save_to_path(file, WriteOptions::default())?;
+        }
+        TagType::VorbisComments => {
+            let mut tag = tagged_file
+                .primary_tag()
+                .cloned()
+                .unwrap_or_else(|| Tag::new(TagType::VorbisComments));
+            tag.insert_text(
+                ItemKey::Unknown(FMPS_RATING_KEYS[0].to_string()),
+                format!("{rating:.2}"),
+            );
+            tag.insert_text(
+                ItemKey::Popularimeter,
+                ((rating * vorbis_scale).round() as u32).to_string(),
+            );
+            tag.save_to_path(file, WriteOptions::default())?;
+        }
+        other => {
+            return Err(anyhow!(
+                "Writing ratings to {:?} tags isn't supported",
+                other
+            ))
+        }
+    }
+    Ok(())
+}
+
+fn read_mpeg_file(file: &Path) -> Result<MpegFile> {
+    Ok(MpegFile::read_from(
+        &mut File::open(file)?,
+        ParseOptions::new(),
+    )?)
+}
+
+pub fn load_rating_thresholds(settings: &Config) -> RatingThresholds {
+    let default = RatingThresholds::default();
+    let thresholds = RatingThresholds {
+        love: settings
+            .get_float("rating_love_threshold")
+            .unwrap_or(default.love),
+        hate: settings
+            .get_float("rating_hate_threshold")
+            .unwrap_or(default.hate),
+    };
+    if thresholds.hate >= thresholds.love {
+        error!("The rating hate threshold must be below the love threshold");
+        exit(1)
+    }
+    thresholds
+}
+
+pub fn load_vorbis_rating_scale(settings: &Config) -> f64 {
+    let scale = settings
+        .get_float("rating_vorbis_scale")
+        .unwrap_or(DEFAULT_VORBIS_RATING_SCALE);
+    if scale <= 0.0 {
+        error!("The Vorbis rating scale must be above 0");
+        exit(1)
+    }
+    scale
+}
+
+/// Finds the files in a playlist with a recording MBID in their tags, and the user's loves and hates
+pub async fn load_files_and_feedback(
+    client: &ListenbrainzClient,
+    file: &PathBuf,
+) -> (Vec<(PathBuf, Uuid)>, HashMap<Uuid, Feedback>) {
+    if !file.exists() {
+        error!("Given playlist file doesn't exist");
+        exit(1);
+    }
+    let files = load_file_paths(file);
+    let number_of_files = files.len();
+    let tagged_files: Vec<(PathBuf, Uuid)> = files
+        .into_iter()
+        .filter_map(|path| {
+            audio_data::read_mbid_from_metadata(&path)
+                .ok()
+                .map(|mbid| (path, mbid))
+        })
+        .collect();
+    info!(
+        "{}/{} files have a recording MBID in their tags",
+        tagged_files.len(),
+        number_of_files
+    );
+    if tagged_files.len() < number_of_files {
+        info!("Upload the playlist with --write-tags to add MBIDs to the rest");
+    }
+
+    let user_name = resolve_user_name(client).await;
+    match get_all_feedback(client, &user_name).await {
+        Ok(feedback) => (tagged_files, feedback),
+        Err(e) => {
+            error!("Could not get existing feedback: {}", e);
+            exit(1)
+        }
+    }
+}
+
+pub async fn push_ratings(
+    client: &ListenbrainzClient,
+    settings: &Config,
+    args: &Args,
+    file: &PathBuf,
+    dry_run: bool,
+) {
+    let thresholds = load_rating_thresholds(settings);
+    let vorbis_scale = load_vorbis_rating_scale(settings);
+    if !file.exists() {
+        error!("Given playlist file doesn't exist");
+        exit(1);
+    }
+    let mut ratings = HashMap::new();
+    for path in load_file_paths(file) {
+        match read_rating(&path, vorbis_scale) {
+            Ok(Some(r)) => {
+                ratings.insert(path, r);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Could not read rating from {:?}: {}", path, e),
+        }
+    }
+    if ratings.is_empty() {
+        info!("No files in the playlist have a rating");
+        return;
+    }
+
+    let user_name = resolve_user_name(client).await;
+    let all_feedback = match get_all_feedback(client, &user_name).await {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Could not get existing feedback: {}", e);
+            exit(1)
+        }
+    };
+    // Files without an MBID in their tags are looked up the same way as when uploading
+    let context = UploadContext::load(client, settings, args, user_name).await;
+    let tracks = ratings
+        .keys()
+        .cloned()
+        .map(PlayerTrack::from_location)
+        .collect();
+    let resolved_songs = match load_and_resolve_songs(
+        client,
+        &context,
+        tracks,
+        args.concurrency,
+        DedupePolicy::Allow,
+    )
+    .await
+    {
+        Ok(songs) => songs,
+        Err(e) => {
+            error!("{}", e);
+            exit(1)
+        }
+    };
+    let resolved_files: HashSet<&PathBuf> = resolved_songs.iter().map(|s| &s.file_path).collect();
+    for path in ratings.keys().filter(|p| !resolved_files.contains(p)) {
+        warn!(
+            "Skipping {:?}, which could not be matched to a recording",
+            path
+        );
+    }
+
+    let mut changes = Vec::new();
+    for song in &resolved_songs {
+        let wanted = thresholds.feedback_for(ratings[&song.file_path]);
+        let current = current_feedback(&song.mbid, &all_feedback);
+        if wanted != current {
+            info!("{:?}: {:?} -> {:?}", song.file_path, current, wanted);
+            changes.push((song.mbid, wanted));
+        }
+    }
+    // Only overwriting a love with a hate, or the other way around, is checked with the user
+    let conflicting: Vec<Uuid> = changes
+        .iter()
+        .filter(|(m, f)| is_feedback_conflict(current_feedback(m, &all_feedback), *f))
+        .map(|(m, _)| *m)
+        .collect();
+    let mut overwritten = HashSet::new();
+    for wanted in [Feedback::Love, Feedback::Hate] {
+        overwritten.extend(resolve_feedback_conflicts(
+            &conflicting,
+            &all_feedback,
+            wanted,
+            args.feedback_conflict,
+            args.no_confirm,
+        ));
+    }
+    changes.retain(|(m, f)| {
+        !is_feedback_conflict(current_feedback(m, &all_feedback), *f) || overwritten.contains(m)
+    });
+    if changes.is_empty() {
+        info!("All ratings already match the feedback on ListenBrainz");
+        return;
+    }
+    if dry_run {
+        info!("Dry run, {} changes were not sent", changes.len());
+        return;
+    }
+    let progress_bar = make_progress_bar(changes.len());
+    for (mbid, feedback) in changes {
+        if let Err(e) = feedback::give_song_feedback_for_mbid(client, &mbid, feedback).await {
+            error!("Could not give feedback on {}: {}", mbid, e);
+        }
+        progress_bar.inc(1);
+    }
+    progress_bar.finish();
+}
+
+pub async fn pull_ratings(
+    client: &ListenbrainzClient,
+    settings: &Config,
+    file: &PathBuf,
+    dry_run: bool,
+) {
+    let thresholds = load_rating_thresholds(settings);
+    let vorbis_scale = load_vorbis_rating_scale(settings);
+    let (tagged_files, all_feedback) = load_files_and_feedback(client, file).await;
+
+    let mut written = 0;
+    for (path, mbid) in &tagged_files {
+        let feedback = current_feedback(mbid, &all_feedback);
+        // Neutral feedback says nothing about how a song should be rated
+        let Some(rating) = thresholds.rating_for(feedback) else {
+            continue;
+        };
+        match read_rating(path, vorbis_scale) {
+            Ok(Some(r)) if thresholds.feedback_for(r) == feedback => continue,
+            Err(e) => {
+                warn!("Could not read rating from {:?}: {}", path, e);
+                continue;
+            }
+            _ => {}
+        }
+        info!("{:?}: rating as {:?}", path, feedback);
+        written += 1;
+        if !dry_run {
+            if let Err(e) = write_rating(path, rating, vorbis_scale) {
+                error!("Could not write rating to {:?}: {}", path, e);
+            }
+        }
+    }
+    if written == 0 {
+        info!("All ratings already match the feedback on ListenBrainz");
+    } else if dry_run {
+        info!("Dry run, {} ratings were not written", written);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tag_writer::test::make_test_mp3;
+    use std::fs;
+
+    fn make_test_flac(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("lpu-{}-{}.flac", std::process::id(), name));
+        // STREAMINFO for 4096 sample blocks, 44.1kHz, stereo, 16 bits and no audio, then padding
+        let mut contents = b"fLaC".to_vec();
+        contents.extend_from_slice(&[0x00, 0x00, 0x00, 0x22, 0x10, 0x00, 0x10, 0x00]);
+        contents.extend_from_slice(&[0x00; 6]);
+        contents.extend_from_slice(&[0x0A, 0xC4, 0x42, 0xF0]);
+        contents.extend_from_slice(&[0x00; 20]);
+        contents.extend_from_slice(&[0x81, 0x00, 0x00, 0x10]);
+        contents.extend_from_slice(&[0x00; 16]);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let thresholds = RatingThresholds::default();
+        assert_eq!(thresholds.feedback_for(1.0), Feedback::Love);
+        assert_eq!(thresholds.feedback_for(0.6), Feedback::Neutral);
+        assert_eq!(thresholds.feedback_for(0.1), Feedback::Hate);
+        for feedback in [Feedback::Love, Feedback::Hate] {
+            let rating = thresholds.rating_for(feedback).unwrap();
+            assert_eq!(thresholds.feedback_for(rating), feedback);
+        }
+        assert!(thresholds.rating_for(Feedback::Hate).unwrap() < thresholds.hate);
+    }
+
+    #[test]
+    fn test_pulled_ratings_push_the_same_feedback() {
+        let all_thresholds = [
+            RatingThresholds::default(),
+            RatingThresholds {
+                love: 1.0,
+                hate: 0.0,
+            },
+        ];
+        for thresholds in all_thresholds {
+            for feedback in [Feedback::Love, Feedback::Hate] {
+                let path = make_test_mp3("rating-round-trip");
+                let rating = thresholds.rating_for(feedback).unwrap();
+                write_rating(&path, rating, DEFAULT_VORBIS_RATING_SCALE).unwrap();
+                let read = read_rating(&path, DEFAULT_VORBIS_RATING_SCALE)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(thresholds.feedback_for(read), feedback);
+
+                // Players that only read POPM see the same feedback
+                let mut id3v2_tag = read_mpeg_file(&path).unwrap().id3v2().cloned().unwrap();
+                id3v2_tag.remove_user_text(FMPS_RATING_KEYS[1]);
+                id3v2_tag
+                    .save_to_path(&path, WriteOptions::default())
+                    .unwrap();
+                let read = read_rating(&path, DEFAULT_VORBIS_RATING_SCALE)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(thresholds.feedback_for(read), feedback);
+                fs::remove_file(path).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_vorbis_hate_reads_back_as_hate() {
+        let thresholds = RatingThresholds {
+            love: 0.9,
+            hate: 0.05,
+        };
+        let path = make_test_flac("vorbis-hate");
+        let rating = thresholds.rating_for(Feedback::Hate).unwrap();
+        write_rating(&path, rating, DEFAULT_VORBIS_RATING_SCALE).unwrap();
+
+        // A small RATING isn't taken to be out of 5 without the FMPS rating next to it
+        let mut tag = lofty::read_from_path(&path)
+            .unwrap()
+            .primary_tag()
+            .cloned()
+            .unwrap();
+        tag.remove_key(&ItemKey::Unknown(FMPS_RATING_KEYS[0].to_string()));
+        tag.save_to_path(&path, WriteOptions::default()).unwrap();
+        let read = read_rating(&path, DEFAULT_VORBIS_RATING_SCALE)
+            .unwrap()
+            .unwrap();
+        assert_eq!(thresholds.feedback_for(read), Feedback::Hate);
+
+        let read = read_rating(&path, 5.0).unwrap().unwrap();
+        assert_eq!(thresholds.feedback_for(read), Feedback::Neutral);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_and_read_mp3_rating() {
+        let path = make_test_mp3("rating");
+        assert_eq!(
+            read_rating(&path, DEFAULT_VORBIS_RATING_SCALE).unwrap(),
+            None
+        );
+
+        write_rating(&path, 1.0, DEFAULT_VORBIS_RATING_SCALE).unwrap();
+        assert_eq!(
+            read_rating(&path, DEFAULT_VORBIS_RATING_SCALE).unwrap(),
+            Some(1.0)
+        );
+
+        // The POPM frame is still there without the FMPS rating
+        let mut id3v2_tag = read_mpeg_file(&path).unwrap().id3v2().cloned().unwrap();
+        id3v2_tag.remove_user_text(FMPS_RATING_KEYS[1]);
+        id3v2_tag
+            .save_to_path(&path, WriteOptions::default())
+            .unwrap();
+        assert_eq!(
+            read_rating(&path, DEFAULT_VORBIS_RATING_SCALE).unwrap(),
+            Some(1.0)
+        );
+
+        write_rating(&path, 0.2, DEFAULT_VORBIS_RATING_SCALE).unwrap();
+        let rating = read_rating(&path, DEFAULT_VORBIS_RATING_SCALE)
+            .unwrap()
+            .unwrap();
+        assert!((rating - 0.2).abs() < 0.01);
+        fs::remove_file(path).unwrap();
+    }
+}
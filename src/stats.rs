@@ -0,0 +1,201 @@
+use crate::listenbrainz_client::ListenbrainzClient;
+use crate::paginator::{fetch_all_pages, Page};
+use anyhow::Result;
+use serde::Deserialize;
+use uuid::Uuid;
+
+// The most items ListenBrainz sends in one page of statistics or recommendations
+const RECORDINGS_PER_PAGE: usize = 1000;
+
+#[derive(Deserialize)]
+struct TopRecordingsResponse {
+    payload: TopRecordingsPayload,
+}
+
+#[derive(Deserialize)]
+struct TopRecordingsPayload {
+    recordings: Vec<RecordingResponse>,
+    total_recording_count: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct RecommendationsResponse {
+    payload: RecommendationsPayload,
+}
+
+#[derive(Deserialize)]
+struct RecommendationsPayload {
+    mbids: Vec<RecordingResponse>,
+    total_mbid_count: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct RecordingResponse {
+    recording_mbid: Option<Uuid>,
+    // Only statistics name the recordings, recommendations just give MBIDs
+    artist_name: Option<String>,
+    track_name: Option<String>,
+}
+
+/// A recording from statistics or recommendations, with its names if ListenBrainz gave them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recording {
+    pub mbid: Uuid,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+}
+
+impl RecordingResponse {
+    fn into_recording(self) -> Option<Recording> {
+        Some(Recording {
+            mbid: self.recording_mbid?,
+            artist: self.artist_name,
+            title: self.track_name,
+        })
+    }
+}
+
+/// Gets the user's most listened recordings over a range such as `all_time` or `this_month`,
+/// most listened first
+pub async fn get_top_recordings(
+    listenbrainz_client: &ListenbrainzClient,
+    user_name: &str,
+    range: &str,
+    count: usize,
+) -> Result<Vec<Recording>> {
+    fetch_recordings(
+        listenbrainz_client,
+        &format!("https://api.listenbrainz.org/1/stats/user/{user_name}/recordings?range={range}"),
+        count,
+        parse_top_recordings_page,
+    )
+    .await
+}
+
+/// Gets the recordings recommended to the user, best first
+pub async fn get_recommended_recordings(
+    listenbrainz_client: &ListenbrainzClient,
+    user_name: &str,
+    count: usize,
+) -> Result<Vec<Recording>> {
+    fetch_recordings(
+        listenbrainz_client,
+        &format!("https://api.listenbrainz.org/1/cf/recommendation/user/{user_name}/recording"),
+        count,
+        parse_recommendations_page,
+    )
+    .await
+}
+
+async fn fetch_recordings(
+    listenbrainz_client: &ListenbrainzClient,
+    base_url: &str,
+    count: usize,
+    parse_page: fn(&str) -> Result<Page<Option<Recording>>>,
+) -> Result<Vec<Recording>> {
+    let per_page = count.min(RECORDINGS_PER_PAGE);
+    let mut recordings = fetch_all_pages(listenbrainz_client, base_url, per_page, |json| {
+        // Stopping at the wanted count, rather than going through every recording
+        let mut page = parse_page(json)?;
+        page.total_count = Some(page.total_count.map_or(count, |t| t.min(count)));
+        Ok(page)
+    })
+    .await?;
+    recordings.truncate(count);
+    Ok(recordings.into_iter().flatten().collect())
+}
+
+// Both endpoints answer with no content at all when nothing has been calculated for the user yet
+fn empty_page<T>() -> Page<T> {
+    Page {
+        items: Vec::new(),
+        count: 0,
+        total_count: Some(0),
+    }
+}
+
+fn parse_top_recordings_page(json: &str) -> Result<Page<Option<Recording>>> {
+    if json.trim().is_empty() {
+        return Ok(empty_page());
+    }
+    let response: TopRecordingsResponse = serde_json::from_str(json)?;
+    let items: Vec<Option<Recording>> = response
+        .payload
+        .recordings
+        .into_iter()
+        .map(RecordingResponse::into_recording)
+        .collect();
+    Ok(Page {
+        count: items.len(),
+        items,
+        total_count: response.payload.total_recording_count,
+    })
+}
+
+fn parse_recommendations_page(json: &str) -> Result<Page<Option<Recording>>> {
+    if json.trim().is_empty() {
+        return Ok(empty_page());
+    }
+    let response: RecommendationsResponse = serde_json::from_str(json)?;
+    let items: Vec<Option<Recording>> = response
+        .payload
+        .mbids
+        .into_iter()
+        .map(RecordingResponse::into_recording)
+        .collect();
+    Ok(Page {
+        count: items.len(),
+        items,
+        total_count: response.payload.total_mbid_count,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_parse_top_recordings_page() {
+        let json = r#"{"payload": {"count": 2, "offset": 0, "range": "all_time",
+            "total_recording_count": 120, "user_id": "Serene-Arc", "recordings": [
+            {"artist_name": "Ed Sheeran", "track_name": "Perfect", "listen_count": 50,
+             "recording_mbid": "36855a5c-abcb-4740-9154-361af8c11ee1"},
+            {"artist_name": "Someone", "track_name": "Unmatched", "listen_count": 40,
+             "recording_mbid": null}
+        ]}}"#;
+        let page = parse_top_recordings_page(json).unwrap();
+        assert_eq!(page.count, 2);
+        assert_eq!(page.total_count, Some(120));
+        assert_eq!(
+            page.items,
+            vec![
+                Some(Recording {
+                    mbid: Uuid::from_str("36855a5c-abcb-4740-9154-361af8c11ee1").unwrap(),
+                    artist: Some("Ed Sheeran".to_string()),
+                    title: Some("Perfect".to_string()),
+                }),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_recommendations_page() {
+        let json = r#"{"payload": {"count": 1, "entity": "recording", "offset": 0,
+            "total_mbid_count": 1000, "user_name": "Serene-Arc", "mbids": [
+            {"recording_mbid": "00066722-b23a-48e5-82e4-0470c82a2705", "score": 0.9}
+        ]}}"#;
+        let page = parse_recommendations_page(json).unwrap();
+        assert_eq!(page.total_count, Some(1000));
+        assert_eq!(
+            page.items,
+            vec![Some(Recording {
+                mbid: Uuid::from_str("00066722-b23a-48e5-82e4-0470c82a2705").unwrap(),
+                artist: None,
+                title: None,
+            })]
+        );
+        assert_eq!(parse_recommendations_page("").unwrap().count, 0);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::audio_data::{get_artist_mbid, AudioIDData, ResolvedSong};
+use anyhow::{anyhow, Result};
+use lofty::config::WriteOptions;
+use lofty::file::TaggedFileExt;
+use lofty::id3::v2::{Frame, Id3v2Tag, UniqueFileIdentifierFrame};
+use lofty::tag::{ItemKey, Tag, TagExt, TagType};
+use log::{debug, error, info, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TagWriteOptions {
+    pub dry_run: bool,
+    pub backup: bool,
+    pub force: bool,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum TagWriteOutcome {
+    Written,
+    WouldWrite,
+    AlreadyPresent,
+    ExistingMbid(String),
+}
+
+pub async fn write_tags_for_resolved_songs(songs: &[ResolvedSong], options: TagWriteOptions) {
+    let mut written = 0;
+    for song in songs {
+        let artist_mbid = match &song.source {
+            // Songs that were resolved from an MBID tag already have what we would write
+            AudioIDData::Mbid(_) => continue,
+            AudioIDData::AudioFileData(file_data) => {
+                get_artist_mbid(file_data.artist.clone()).await.mbid
+            }
+            AudioIDData::Fingerprint(_) => None,
+            AudioIDData::InferredFileData(_) if !options.force => {
+                debug!(
+                    "Not writing MBID to {:?}, it was only matched by its path",
+                    song.file_path
+                );
+                continue;
+            }
+            AudioIDData::InferredFileData(file_data) => {
+                get_artist_mbid(file_data.artist.clone()).await.mbid
+            }
+        };
+        match write_mbids_to_file(&song.file_path, &song.mbid, artist_mbid.as_ref(), options) {
+            Ok(TagWriteOutcome::Written) => {
+                debug!("Wrote MBID {} to {:?}", song.mbid, song.file_path);
+                written += 1;
+            }
+            Ok(TagWriteOutcome::WouldWrite) => {
+                info!("Would write MBID {} to {:?}", song.mbid, song.file_path);
+            }
+            Ok(TagWriteOutcome::AlreadyPresent) => {
+                debug!("File {:?} already has MBID {}", song.file_path, song.mbid);
+            }
+            Ok(TagWriteOutcome::ExistingMbid(existing)) => {
+                warn!(
+                    "Skipping {:?}, it already has MBID {} which differs from resolved {}",
+                    song.file_path, existing, song.mbid
+                );
+            }
+            Err(e) => {
+                error!("Could not write tags to {:?}: {}", song.file_path, e);
+            }
+        }
+    }
+    if !options.dry_run {
+        info!("Wrote MBIDs to {} files", written);
+    }
+}
+
+pub fn write_mbids_to_file(
+    file: &Path,
+    recording_mbid: &Uuid,
+    artist_mbid: Option<&Uuid>,
+    options: TagWriteOptions,
+) -> Result<TagWriteOutcome> {
+    let mut tagged_file = lofty::read_from_path(file)?;
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+    let tag = tagged_file
+        .primary_tag_mut()
+        .ok_or_else(|| anyhow!("Could not create tag for file"))?;
+
+    if let Some(existing) = tag.get_string(&ItemKey::MusicBrainzRecordingId) {
+        if existing == recording_mbid.to_string() {
+            return Ok(TagWriteOutcome::AlreadyPresent);
+        }
+        if !options.force {
+            return Ok(TagWriteOutcome::ExistingMbid(existing.to_string()));
+        }
+    }
+
+    if options.dry_run {
+        return Ok(TagWriteOutcome::WouldWrite);
+    }
+
+    if options.backup {
+        let backup = backup_path(file);
+        debug!("Backing up {:?} to {:?}", file, backup);
+        fs::copy(file, backup)?;
+    }
+
+    tag.insert_text(ItemKey::MusicBrainzRecordingId, recording_mbid.to_string());
+    if let Some(artist_mbid) = artist_mbid {
+        if options.force || tag.get_string(&ItemKey::MusicBrainzArtistId).is_none() {
+            tag.insert_text(ItemKey::MusicBrainzArtistId, artist_mbid.to_string());
+        }
+    }
+    save_tag(tag.clone(), recording_mbid, file)?;
+    Ok(TagWriteOutcome::Written)
+}
+
+fn save_tag(tag: Tag, recording_mbid: &Uuid, file: &Path) -> Result<()> {
+    match tag.tag_type() {
+        TagType::Id3v2 => {
+            // The generic conversion drops the recording MBID, since ID3v2 keeps it in a UFID frame
+            let mut id3v2_tag = Id3v2Tag::from(tag);
+            id3v2_tag.insert(Frame::UniqueFileIdentifier(UniqueFileIdentifierFrame::new(
+                "http://musicbrainz.org".to_string(),
+                recording_mbid.to_string().into_bytes(),
+            )));
+            id3v2_tag.save_to_path(file, WriteOptions::default())?
+        }
+        _ => tag.save_to_path(file, WriteOptions::default())?,
+    }
+    Ok(())
+}
+
+fn backup_path(file: &Path) -> PathBuf {
+    let mut backup = file.as_os_str().to_owned();
+    backup.push(".bak");
+    PathBuf::from(backup)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::audio_data::read_mbid_from_metadata;
+    use std::str::FromStr;
+
+    /// Creates a silent MP3 with no tags in the temporary directory
+    pub fn make_test_mp3(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("lpu-{}-{}.mp3", std::process::id(), name));
+        // MPEG-1 Layer III, 128kbps, 44.1kHz; each frame is 417 bytes
+        let mut frame = vec![0u8; 417];
+        frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x64]);
+        fs::write(&path, frame.repeat(10)).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_write_mbid_to_untagged_file() {
+        let path = make_test_mp3("write");
+        let mbid = Uuid::from_str("36855a5c-abcb-4740-9154-361af8c11ee1").unwrap();
+        let result = write_mbids_to_file(&path, &mbid, None, TagWriteOptions::default()).unwrap();
+        assert_eq!(result, TagWriteOutcome::Written);
+        assert_eq!(read_mbid_from_metadata(&path).unwrap(), mbid);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_mbid_dry_run() {
+        let path = make_test_mp3("dry-run");
+        let mbid = Uuid::from_str("36855a5c-abcb-4740-9154-361af8c11ee1").unwrap();
+        let options = TagWriteOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let result = write_mbids_to_file(&path, &mbid, None, options).unwrap();
+        assert_eq!(result, TagWriteOutcome::WouldWrite);
+        assert!(read_mbid_from_metadata(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_mbid_existing_not_overwritten() {
+        let path = make_test_mp3("existing");
+        let first = Uuid::from_str("36855a5c-abcb-4740-9154-361af8c11ee1").unwrap();
+        let second = Uuid::from_str("00066722-b23a-48e5-82e4-0470c82a2705").unwrap();
+        write_mbids_to_file(&path, &first, None, TagWriteOptions::default()).unwrap();
+
+        let result = write_mbids_to_file(&path, &second, None, TagWriteOptions::default()).unwrap();
+        assert_eq!(result, TagWriteOutcome::ExistingMbid(first.to_string()));
+        assert_eq!(read_mbid_from_metadata(&path).unwrap(), first);
+
+        let original_contents = fs::read(&path).unwrap();
+        let options = TagWriteOptions {
+            force: true,
+            backup: true,
+            ..Default::default()
+        };
+        let result = write_mbids_to_file(&path, &second, None, options).unwrap();
+        assert_eq!(result, TagWriteOutcome::Written);
+        assert_eq!(read_mbid_from_metadata(&path).unwrap(), second);
+        assert_eq!(fs::read(backup_path(&path)).unwrap(), original_contents);
+        fs::remove_file(backup_path(&path)).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,1179 @@
+use crate::acoustid_client::{AcoustIdClient, DEFAULT_ACOUSTID_URL};
+use crate::audio_data::{AudioFileData, AudioIDData, ResolvedSong};
+use crate::beets::{open_beets_library, BeetsLibrary};
+use crate::duplicate_name::{
+    DuplicateNameTemplate, TemplateValues, DEFAULT_DUPLICATE_NAME_TEMPLATE,
+};
+use crate::feedback::{
+    current_feedback, get_all_feedback, give_feedback_concurrently, is_feedback_conflict,
+    resolve_feedback_conflicts,
+};
+use crate::journal::Journal;
+use crate::library::{open_existing_library_index, LibraryIndex};
+use crate::listenbrainz_client::ListenbrainzClient;
+use crate::path_pattern::PathPattern;
+use crate::players::{PlayerPlaylist, PlayerTrack};
+use crate::playlist::{
+    delete_items_from_playlist, edit_playlist, get_current_playlists, get_current_user,
+    get_full_specific_playlist, mass_add_to_playlist, FullExistingPlaylistResponse, PlaylistEdit,
+    PlaylistMetadata, PlaylistTrack, SimpleExistingPlaylistResponse, MAX_TRACKS_PER_REQUEST,
+};
+use crate::tag_writer::TagWriteOptions;
+use crate::{
+    audio_data, calculate_percentage, fingerprint, make_progress_bar, path_pattern, players,
+    playlist, tag_writer, visibility, Args, DedupePolicy, DuplicateAction, Feedback,
+    TrackAnnotation,
+};
+use anyhow::{anyhow, Result};
+use config::Config;
+use futures::stream::{self, StreamExt};
+use inquire::{Confirm, Select};
+use log::{debug, error, info, warn};
+use m3u::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::Arc;
+use uuid::Uuid;
+
+pub const LOOKUP_BATCH_SIZE: usize = 50;
+
+pub const FEEDBACK_CHECKPOINT_INTERVAL: usize = 50;
+
+/// Upload settings that can come from the command line or the configuration file
+pub struct UploadSettings {
+    pub chunk_size: usize,
+    pub duplicate_name_template: DuplicateNameTemplate,
+}
+
+impl UploadSettings {
+    pub fn load(args: &Args, settings: &Config) -> Self {
+        let chunk_size = args
+            .chunk_size
+            .or(settings.get::<NonZeroUsize>("playlist_chunk_size").ok())
+            .map_or(MAX_TRACKS_PER_REQUEST, NonZeroUsize::get);
+        if chunk_size > MAX_TRACKS_PER_REQUEST {
+            warn!(
+                "ListenBrainz accepts at most {} tracks per request, using that as the chunk size",
+                MAX_TRACKS_PER_REQUEST
+            );
+        }
+        let template = args
+            .duplicate_name_template
+            .clone()
+            .or(settings.get_string("duplicate_name_template").ok())
+            .unwrap_or(DEFAULT_DUPLICATE_NAME_TEMPLATE.to_string());
+        let duplicate_name_template = DuplicateNameTemplate::new(&template).unwrap_or_else(|e| {
+            error!("Invalid duplicate name template: {}", e);
+            exit(1)
+        });
+        UploadSettings {
+            chunk_size: chunk_size.min(MAX_TRACKS_PER_REQUEST),
+            duplicate_name_template,
+        }
+    }
+}
+
+pub fn build_acoustid_client(settings: &Config, fingerprint: bool) -> Option<AcoustIdClient> {
+    if !fingerprint {
+        return None;
+    }
+    let Ok(api_key) = settings.get_string("acoustid_api_key") else {
+        error!("Fingerprinting needs an AcoustID API key in the configuration!");
+        exit(1)
+    };
+    let lookup_url = settings
+        .get_string("acoustid_url")
+        .unwrap_or(DEFAULT_ACOUSTID_URL.to_string());
+    Some(AcoustIdClient::new(api_key, lookup_url))
+}
+
+pub fn load_path_patterns(settings: &Config) -> Vec<PathPattern> {
+    settings
+        .get::<Vec<String>>("filename_patterns")
+        .unwrap_or_default()
+        .iter()
+        .map(|p| PathPattern::new(p))
+        .collect::<Result<_>>()
+        .unwrap_or_else(|e| {
+            error!("Invalid filename pattern in configuration: {}", e);
+            exit(1)
+        })
+}
+
+/// Uploads a playlist file, or the playlists of a music player's library file
+pub async fn upload_playlists(
+    client: &ListenbrainzClient,
+    settings: &Config,
+    args: &Args,
+    upload: &UploadSettings,
+    file: &PathBuf,
+) {
+    if !file.exists() {
+        error!("Given playlist file doesn't exist");
+        exit(1);
+    }
+
+    let playlists = match players::read_playlist_library(file) {
+        Ok(Some(playlists)) => Some(select_library_playlists(args, file, playlists)),
+        Ok(None) => None,
+        Err(e) => {
+            error!("Could not read the playlists in {:?}: {}", file, e);
+            exit(1)
+        }
+    };
+
+    debug!("Testing token by resolving to user");
+    let user_name = match get_current_user(client).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Could not resolve token successfully: {}", e);
+            exit(1);
+        }
+    };
+    info!("This token belongs to {}!", &user_name);
+    let context = UploadContext::load(client, settings, args, user_name).await;
+
+    match playlists {
+        None => {
+            if let Err(e) =
+                upload_playlist_file(client, args, upload, &context, file, None, None).await
+            {
+                error!("{}", e);
+                exit(1)
+            }
+        }
+        Some(playlists) => {
+            // Each playlist's own journal is removed once it is uploaded, so the library keeps
+            // a journal of the playlists that finished for --resume to skip
+            let canonical_file = file.canonicalize().unwrap_or_else(|_| file.clone());
+            let library_source = format!("library {}", canonical_file.display());
+            let mut library_journal = match Journal::open(
+                Journal::default_path(&library_source),
+                &library_source,
+                "",
+                args.resume,
+            ) {
+                Ok(j) => j,
+                Err(e) => {
+                    error!("Could not open journal: {}", e);
+                    exit(1)
+                }
+            };
+            // One playlist failing doesn't stop the rest from being uploaded
+            let mut failed = Vec::new();
+            for playlist in &playlists {
+                if library_journal.playlists_finished.contains(&playlist.name) {
+                    info!(
+                        "Skipping '{}', already uploaded by the run being resumed",
+                        playlist.name
+                    );
+                    continue;
+                }
+                info!("Uploading '{}'", playlist.name);
+                match upload_playlist_file(
+                    client,
+                    args,
+                    upload,
+                    &context,
+                    file,
+                    Some(playlist),
+                    None,
+                )
+                .await
+                {
+                    Ok(()) => {
+                        library_journal
+                            .playlists_finished
+                            .insert(playlist.name.clone());
+                        library_journal.checkpoint();
+                    }
+                    Err(e) => {
+                        error!("Could not upload '{}': {}", playlist.name, e);
+                        failed.push(playlist.name.as_str());
+                    }
+                }
+            }
+            info!(
+                "Uploaded {}/{} playlists",
+                playlists.len() - failed.len(),
+                playlists.len()
+            );
+            if !failed.is_empty() {
+                error!("These playlists were not uploaded: {}", failed.join(", "));
+                error!("Run again with --resume to retry them");
+                exit(1)
+            }
+            if let Err(e) = library_journal.finish() {
+                warn!("Could not remove journal: {}", e);
+            }
+        }
+    }
+}
+
+/// Who a playlist is uploaded for, and what it came from
+pub struct UploadTarget<'a> {
+    pub user_name: &'a String,
+    // Fills `{source}` in duplicate names: a playlist file, a player's playlist or an export
+    pub source: &'a str,
+}
+
+/// What every playlist of an upload is resolved and checked against, set up once for all of them
+pub struct UploadContext {
+    pub acoustid_client: Option<AcoustIdClient>,
+    pub path_patterns: Vec<PathPattern>,
+    pub known_files: KnownFiles,
+    pub user_name: String,
+    // Only fetched when feedback is given
+    pub given_feedback: Option<HashMap<Uuid, Feedback>>,
+}
+
+impl UploadContext {
+    pub async fn load(
+        client: &ListenbrainzClient,
+        settings: &Config,
+        args: &Args,
+        user_name: String,
+    ) -> Self {
+        let given_feedback = match args.feedback {
+            Some(_) => match get_all_feedback(client, &user_name).await {
+                Ok(f) => Some(f),
+                Err(e) => {
+                    error!("Could not get existing feedback: {}", e);
+                    exit(1)
+                }
+            },
+            None => None,
+        };
+        UploadContext {
+            acoustid_client: build_acoustid_client(settings, args.fingerprint),
+            path_patterns: load_path_patterns(settings),
+            known_files: KnownFiles::open(settings, args),
+            user_name,
+            given_feedback,
+        }
+    }
+}
+
+/// Picks the playlists of a music player's library to upload, either the one named or all of them
+pub fn select_library_playlists(
+    args: &Args,
+    file: &Path,
+    playlists: Vec<PlayerPlaylist>,
+) -> Vec<PlayerPlaylist> {
+    if args.playlist_name_from_source {
+        let (playlists, empty): (Vec<_>, Vec<_>) =
+            playlists.into_iter().partition(|p| !p.tracks.is_empty());
+        for playlist in empty {
+            info!("Skipping '{}', which has no local files", playlist.name);
+        }
+        return playlists;
+    }
+    let Some(name) = &args.playlist_name else {
+        error!(
+            "{:?} is a library of playlists, give the name of one or use --playlist-name-from-source",
+            file
+        );
+        exit(1)
+    };
+    match playlists.into_iter().find(|p| &p.name == name) {
+        Some(playlist) => vec![playlist],
+        None => {
+            error!("No playlist called '{}' in {:?}", name, file);
+            exit(1)
+        }
+    }
+}
+
+/// Uploads a playlist file, or one playlist from a music player's library file, named `title` if
+/// given
+pub async fn upload_playlist_file(
+    client: &ListenbrainzClient,
+    args: &Args,
+    upload: &UploadSettings,
+    context: &UploadContext,
+    file: &PathBuf,
+    source: Option<&PlayerPlaylist>,
+    title: Option<&str>,
+) -> Result<()> {
+    // A music player's file isn't a playlist file, so has no header to read
+    let mut metadata = load_playlist_metadata(args, file, source.is_none());
+    if let Some(source) = source {
+        metadata.title = Some(title.unwrap_or(&source.name).to_string());
+    }
+    let source_name = match source {
+        Some(source) => source.name.clone(),
+        None => file
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    };
+    let Some(playlist_name) = metadata
+        .title
+        .clone()
+        .or(args.playlist_id.map(|id| id.to_string()))
+    else {
+        return Err(anyhow!(
+            "No playlist name given, and none found in the metadata file or playlist header"
+        ));
+    };
+    metadata.title = Some(playlist_name.clone());
+
+    // Each playlist of a library gets its own journal
+    let canonical_file = file.canonicalize().unwrap_or_else(|_| file.clone());
+    let journal_source = match source {
+        Some(source) => format!("{}#{}", canonical_file.display(), source.name),
+        None => canonical_file.display().to_string(),
+    };
+    let journal_path = args
+        .journal
+        .clone()
+        .unwrap_or_else(|| Journal::default_path(&journal_source));
+    let mut journal = Journal::open(journal_path, &journal_source, &playlist_name, args.resume)
+        .map_err(|e| anyhow!("Could not open journal: {}", e))?;
+
+    let resolved_songs = match journal.resolved_songs.clone() {
+        Some(songs) => {
+            info!("Using {} songs resolved in a previous run", songs.len());
+            songs
+        }
+        None => {
+            let songs = load_and_resolve_songs(
+                client,
+                context,
+                source.map_or_else(|| load_playlist_tracks(file), |s| s.tracks.clone()),
+                args.concurrency,
+                args.dedupe,
+            )
+            .await?;
+            journal.resolved_songs = Some(songs.clone());
+            journal.checkpoint();
+            songs
+        }
+    };
+    let musicbrainz_ids: Vec<Uuid> = resolved_songs.iter().map(|s| s.mbid).collect();
+    let tracks: Vec<PlaylistTrack> = resolved_songs
+        .iter()
+        .map(|s| PlaylistTrack {
+            mbid: s.mbid,
+            annotation: args.track_annotation.and_then(|a| annotate_track(a, s)),
+        })
+        .collect();
+
+    let inferred_songs: Vec<_> = resolved_songs
+        .iter()
+        .filter(|s| matches!(s.source, AudioIDData::InferredFileData(_)))
+        .collect();
+    if !inferred_songs.is_empty() {
+        warn!(
+            "{} songs were matched using only their file path and may be wrong:",
+            inferred_songs.len()
+        );
+        for song in inferred_songs {
+            warn!("  {:?} -> {}", song.file_path, song.mbid);
+        }
+    }
+
+    if !args.no_confirm {
+        match Confirm::new("Do you want to continue with the matched songs?")
+            .with_default(true)
+            .prompt()
+        {
+            Ok(true) => {
+                info!("Continuing");
+            }
+            Ok(false) => {
+                return Err(anyhow!("Aborted, the matched songs were not accepted"));
+            }
+            Err(_) => {
+                error!("Error with questionaire");
+            }
+        }
+    }
+
+    if args.write_tags {
+        info!("Writing resolved MBIDs to files...");
+        tag_writer::write_tags_for_resolved_songs(
+            &resolved_songs,
+            TagWriteOptions {
+                dry_run: args.tag_dry_run,
+                backup: args.tag_backup,
+                force: args.force_tags,
+            },
+        )
+        .await;
+    }
+
+    if let Some(playlist_id) = journal.playlist_id {
+        info!("Resuming upload to playlist with ID {}", playlist_id);
+        add_tracks_with_progress(
+            client,
+            &playlist_id,
+            &tracks,
+            upload.chunk_size,
+            &mut journal,
+        )
+        .await
+        .map_err(|e| anyhow!("Could not insert remaining items into playlist: {}", e))?;
+    } else {
+        let target = UploadTarget {
+            user_name: &context.user_name,
+            source: &source_name,
+        };
+        upload_playlist(
+            client,
+            args,
+            upload,
+            &metadata,
+            &target,
+            &tracks,
+            &mut journal,
+        )
+        .await?;
+        if let Some(playlist_id) = journal.playlist_id {
+            debug!("Recorded playlist {} in journal", playlist_id);
+        }
+    }
+
+    if let (Some(f), Some(given_feedback)) = (args.feedback, &context.given_feedback) {
+        let overwritten = resolve_feedback_conflicts(
+            &musicbrainz_ids,
+            given_feedback,
+            f,
+            args.feedback_conflict,
+            args.no_confirm,
+        );
+        let filtered_musicbrainz_ids: Vec<_> = musicbrainz_ids
+            .iter()
+            .filter(|i| {
+                let current = current_feedback(i, given_feedback);
+                current != f
+                    && (!is_feedback_conflict(current, f) || overwritten.contains(*i))
+                    && !journal.feedback_sent.contains(*i)
+            })
+            .collect();
+        let filtered_len = filtered_musicbrainz_ids.len();
+        let total_len = musicbrainz_ids.len();
+        let correct_len = total_len - filtered_len;
+        let percentage = calculate_percentage(&correct_len, &total_len).unwrap();
+        if filtered_len == 0 {
+            info!("All songs in playlist already have the correct feedback");
+        } else {
+            if filtered_len == total_len {
+                info!("Sending feedback for songs in playlist...");
+            } else {
+                info!(
+                    "{}/{} ({:.2}%) of songs already have the correct feedback",
+                    correct_len, total_len, percentage
+                );
+                info!("Sending feedback for remaining songs in playlist...");
+            }
+            give_feedback_on_all_songs(
+                client,
+                filtered_musicbrainz_ids,
+                f,
+                args.concurrency,
+                &mut journal,
+            )
+            .await?;
+        }
+    }
+
+    if let Err(e) = journal.finish() {
+        warn!("Could not remove finished journal: {}", e);
+    }
+    Ok(())
+}
+
+pub async fn upload_playlist(
+    client: &ListenbrainzClient,
+    args: &Args,
+    upload: &UploadSettings,
+    metadata: &PlaylistMetadata,
+    target: &UploadTarget<'_>,
+    tracks: &[PlaylistTrack],
+    journal: &mut Journal,
+) -> Result<()> {
+    let playlist_name = metadata.title.as_deref().unwrap_or_default();
+    if let Some(playlist_id) = args.playlist_id {
+        let p = get_full_specific_playlist(client, &playlist_id)
+            .await
+            .map_err(|e| anyhow!("Could not retrieve playlist with ID {}: {}", playlist_id, e))?;
+        return replace_playlist_songs(client, args, upload, &p, tracks, metadata, journal).await;
+    }
+
+    debug!("Retrieving existing playlists");
+    let current_playlists = get_current_playlists(client, target.user_name)
+        .await
+        .map_err(|e| anyhow!("Could not retrieve existing playlists: {}", e))?;
+    debug!(
+        "Found {} existing playlists on account",
+        current_playlists.len()
+    );
+    let mut new_playlist_name = playlist_name.to_string();
+    let duplicate_playlists: Vec<_> = current_playlists
+        .iter()
+        .filter(|p| p.title == playlist_name)
+        .collect();
+    if duplicate_playlists.is_empty() {
+        info!("No duplicate playlists found");
+        return submit_new_playlist(
+            client,
+            args,
+            upload,
+            tracks,
+            new_playlist_name,
+            metadata,
+            journal,
+        )
+        .await;
+    }
+    for p in &duplicate_playlists {
+        debug!(
+            "Possible duplicate playlist {} by {} (public: {}, created {:?}, last modified {:?})",
+            p.identifier, p.creator, p.public, p.created, p.last_modified
+        );
+    }
+    info!(
+        "Found {} duplicate playlists, enacting duplicate policy",
+        duplicate_playlists.len()
+    );
+    match args.duplicate_action {
+        DuplicateAction::None => {
+            // Just submit new playlist
+            submit_new_playlist(
+                client,
+                args,
+                upload,
+                tracks,
+                new_playlist_name,
+                metadata,
+                journal,
+            )
+            .await
+        }
+        DuplicateAction::Overwrite => {
+            let p = choose_duplicate_playlist(&duplicate_playlists, args.no_confirm)?;
+            let p =
+                FullExistingPlaylistResponse::convert_simple_playlist_response_to_full(client, p)
+                    .await
+                    .map_err(|e| {
+                        anyhow!(
+                    "Could not find more detailed information on possible duplicate playlist: {}",
+                    e
+                )
+                    })?;
+            replace_playlist_songs(client, args, upload, &p, tracks, metadata, journal).await
+        }
+        DuplicateAction::Number => {
+            let values = TemplateValues::new(playlist_name, target.source);
+            new_playlist_name = upload
+                .duplicate_name_template
+                .first_free_name(&values, |name| {
+                    current_playlists.iter().any(|p| p.title == name)
+                });
+            info!("Naming the new playlist '{}'", new_playlist_name);
+            submit_new_playlist(
+                client,
+                args,
+                upload,
+                tracks,
+                new_playlist_name,
+                metadata,
+                journal,
+            )
+            .await
+        }
+        DuplicateAction::Abort => Err(anyhow!("Duplicate action says to abort!")),
+    }
+}
+
+/// Picks which of several playlists sharing a title to use, asking the user if there's a choice
+pub fn choose_duplicate_playlist<'a>(
+    duplicate_playlists: &[&'a SimpleExistingPlaylistResponse],
+    no_confirm: bool,
+) -> Result<&'a SimpleExistingPlaylistResponse> {
+    if duplicate_playlists.len() == 1 {
+        return Ok(duplicate_playlists[0]);
+    }
+    let descriptions: Vec<String> = duplicate_playlists.iter().map(|p| p.describe()).collect();
+    if no_confirm {
+        return Err(anyhow!(
+            "{} playlists are called '{}', choose one with --playlist-id:\n{}",
+            duplicate_playlists.len(),
+            duplicate_playlists[0].title,
+            descriptions.join("\n")
+        ));
+    }
+    let choice = Select::new(
+        "Several playlists have this title, which should be used?",
+        descriptions,
+    )
+    .raw_prompt()
+    .map_err(|e| anyhow!("No playlist chosen: {}", e))?;
+    Ok(duplicate_playlists[choice.index])
+}
+
+pub async fn replace_playlist_songs(
+    client: &ListenbrainzClient,
+    args: &Args,
+    upload: &UploadSettings,
+    p: &FullExistingPlaylistResponse,
+    tracks: &[PlaylistTrack],
+    metadata: &PlaylistMetadata,
+    journal: &mut Journal,
+) -> Result<()> {
+    let changes = PlaylistEdit::from_differences(
+        p,
+        args.playlist_name.clone(),
+        visibility(args.public, args.private),
+        metadata,
+    );
+    if changes.has_changes() {
+        edit_playlist(client, &p.identifier, &changes)
+            .await
+            .map_err(|e| anyhow!("Could not update the details of the playlist: {}", e))?;
+    }
+    if p.number_of_tracks > 0 {
+        delete_items_from_playlist(client, &p.identifier, 0, p.number_of_tracks + 1)
+            .await
+            .map_err(|e| {
+                anyhow!(
+                    "Could not delete items from playlist to overwrite it: {}",
+                    e
+                )
+            })?;
+    } else {
+        debug!("Existing playlist already has no tracks");
+    }
+    journal.playlist_id = Some(p.identifier);
+    journal.checkpoint();
+    add_tracks_with_progress(client, &p.identifier, tracks, upload.chunk_size, journal)
+        .await
+        .map_err(|e| anyhow!("Could not insert new items into playlist: {}", e))?;
+    info!("Replaced songs in playlist with ID {}", p.identifier);
+    Ok(())
+}
+
+pub async fn load_and_resolve_songs(
+    listenbrainz_client: &ListenbrainzClient,
+    context: &UploadContext,
+    playlist_entries: Vec<PlayerTrack>,
+    concurrency: NonZeroUsize,
+    dedupe: DedupePolicy,
+) -> Result<Vec<ResolvedSong>> {
+    let number_of_files = playlist_entries.len();
+    info!("Found {} files in playlist", number_of_files);
+
+    if number_of_files == 0 {
+        return Err(anyhow!("No files read from playlist, aborting"));
+    }
+
+    let acoustid_client = context.acoustid_client.as_ref();
+    let song_data: Vec<_> = playlist_entries
+        .into_iter()
+        .filter_map(|track| {
+            // What a music player knows about a track comes before the file itself
+            let data = track.audio_id_data().or_else(|| {
+                identify_file(
+                    &track.location,
+                    &context.known_files,
+                    acoustid_client.is_some(),
+                    &context.path_patterns,
+                )
+            })?;
+            Some((track.location, data))
+        })
+        .collect();
+    let number_of_tagged_songs = song_data.len();
+    let percentage = calculate_percentage(&number_of_tagged_songs, &number_of_files)
+        .expect("Could not calculate percentage of tagged songs");
+    info!(
+        "{}/{} ({:.2}%) of songs had readable tags or fingerprints",
+        number_of_tagged_songs, number_of_files, percentage,
+    );
+
+    if number_of_tagged_songs == 0 {
+        return Err(anyhow!("No tagged songs could be read, aborting"));
+    }
+
+    info!("Resolving song tags to Musicbrainz IDs...");
+    let resolved_songs = resolve_all_songs_for_mbids(
+        listenbrainz_client,
+        acoustid_client,
+        song_data,
+        concurrency,
+        dedupe,
+    )
+    .await;
+
+    let number_of_resolved_songs = resolved_songs.len();
+    let percentage = calculate_percentage(&number_of_resolved_songs, &number_of_tagged_songs)
+        .expect("Could not calculate percentage of resolved songs");
+    info!(
+        "{}/{} ({:.2}%) of songs were resolved",
+        number_of_resolved_songs, number_of_tagged_songs, percentage,
+    );
+    Ok(resolved_songs)
+}
+
+/// Creates the playlist with the first chunk of tracks, then adds the rest in further chunks
+pub async fn submit_new_playlist(
+    listenbrainz_client: &ListenbrainzClient,
+    args: &Args,
+    upload: &UploadSettings,
+    tracks: &[PlaylistTrack],
+    playlist_name: String,
+    metadata: &PlaylistMetadata,
+    journal: &mut Journal,
+) -> Result<()> {
+    debug!("Submitting new playlist");
+    let initial_tracks = &tracks[..tracks.len().min(upload.chunk_size)];
+    match playlist::submit_playlist(
+        listenbrainz_client,
+        initial_tracks,
+        playlist_name,
+        args.public,
+        metadata,
+    )
+    .await
+    {
+        Ok(r) => {
+            info!("Playlist created with ID {}", r.playlist_mbid);
+            journal.playlist_id = Some(r.playlist_mbid);
+            journal.tracks_added = initial_tracks.len();
+            journal.checkpoint();
+        }
+        Err(e) => return Err(anyhow!("Could not create playlist: {}", e)),
+    }
+    if initial_tracks.len() < tracks.len() {
+        info!(
+            "Adding the remaining {} tracks to the playlist...",
+            tracks.len() - initial_tracks.len()
+        );
+        let playlist_id = journal.playlist_id.expect("Playlist was just created");
+        add_tracks_with_progress(
+            listenbrainz_client,
+            &playlist_id,
+            tracks,
+            upload.chunk_size,
+            journal,
+        )
+        .await
+        .map_err(|e| {
+            anyhow!(
+                "Could not insert remaining items into playlist, run again with --resume to add the rest: {}",
+                e
+            )
+        })?;
+    }
+    Ok(())
+}
+
+pub async fn add_tracks_with_progress(
+    listenbrainz_client: &ListenbrainzClient,
+    playlist_id: &Uuid,
+    tracks: &[PlaylistTrack],
+    chunk_size: usize,
+    journal: &mut Journal,
+) -> Result<()> {
+    let progress_bar = make_progress_bar(tracks.len());
+    let result = mass_add_to_playlist(
+        listenbrainz_client,
+        playlist_id,
+        tracks,
+        chunk_size,
+        journal,
+        &progress_bar,
+    )
+    .await;
+    progress_bar.finish();
+    result
+}
+
+pub async fn give_feedback_on_all_songs(
+    listenbrainz_client: &ListenbrainzClient,
+    musicbrainz_ids: Vec<&Uuid>,
+    feedback: Feedback,
+    concurrency: NonZeroUsize,
+    journal: &mut Journal,
+) -> Result<()> {
+    let changes = musicbrainz_ids
+        .into_iter()
+        .map(|m| (*m, feedback))
+        .collect();
+    let mut sent_since_checkpoint = 0;
+    let failed = give_feedback_concurrently(listenbrainz_client, changes, concurrency, |mbid| {
+        journal.feedback_sent.insert(mbid);
+        sent_since_checkpoint += 1;
+        if sent_since_checkpoint >= FEEDBACK_CHECKPOINT_INTERVAL {
+            journal.checkpoint();
+            sent_since_checkpoint = 0;
+        }
+    })
+    .await;
+    journal.checkpoint();
+    if failed > 0 {
+        // Songs that failed aren't recorded as sent, so a resumed run tries them again
+        return Err(anyhow!(
+            "Could not give feedback on {} songs, run again with --resume to retry",
+            failed
+        ));
+    }
+    Ok(())
+}
+
+pub async fn resolve_all_songs_for_mbids(
+    listenbrainz_client: &ListenbrainzClient,
+    acoustid_client: Option<&AcoustIdClient>,
+    song_data: Vec<(PathBuf, AudioIDData)>,
+    concurrency: NonZeroUsize,
+    dedupe: DedupePolicy,
+) -> Vec<ResolvedSong> {
+    let batch_results = resolve_batch_lookups(
+        listenbrainz_client,
+        song_data.iter().map(|(_, data)| data),
+        concurrency,
+    )
+    .await;
+
+    let progress_bar = make_progress_bar(song_data.len());
+    // Buffered rather than unordered so that the playlist order is kept
+    let resolved_songs: Vec<Result<ResolvedSong>> = stream::iter(song_data.into_iter().enumerate())
+        .map(|(index, (file_path, data))| {
+            let pb = Arc::clone(&progress_bar);
+            let batch_result = batch_results.get(&index).copied();
+            async move {
+                let out = match (&data, batch_result) {
+                    (AudioIDData::Mbid(mbid), _) => Ok(*mbid),
+                    (_, Some(mbid)) => Ok(mbid),
+                    // Misses from the batch lookup get another chance, including artist aliases
+                    (AudioIDData::AudioFileData(d) | AudioIDData::InferredFileData(d), None) => {
+                        audio_data::get_musicbrainz_id_for_audio_data(
+                            listenbrainz_client,
+                            d.clone(),
+                        )
+                        .await
+                    }
+                    (AudioIDData::Fingerprint(f), None) => match acoustid_client {
+                        Some(c) => {
+                            fingerprint::get_musicbrainz_id_for_fingerprint(c, f.clone()).await
+                        }
+                        None => Err(anyhow!("Fingerprinting is not enabled")),
+                    },
+                };
+                pb.inc(1);
+                out.map(|mbid| ResolvedSong {
+                    file_path,
+                    mbid,
+                    source: data,
+                })
+            }
+        })
+        .buffered(concurrency.get())
+        .collect()
+        .await;
+
+    let resolved_songs = resolved_songs
+        .into_iter()
+        .filter_map(|result| match result {
+            Ok(s) => Some(s),
+            Err(e) => {
+                error!("Could not resolve song: {}", e);
+                None
+            }
+        })
+        .collect();
+    dedupe_resolved_songs(resolved_songs, dedupe)
+}
+
+/// Removes repeated recordings, reporting which playlist entries were collapsed into one
+pub fn dedupe_resolved_songs(songs: Vec<ResolvedSong>, policy: DedupePolicy) -> Vec<ResolvedSong> {
+    let mut positions: HashMap<Uuid, Vec<usize>> = HashMap::new();
+    for (index, song) in songs.iter().enumerate() {
+        positions.entry(song.mbid).or_default().push(index);
+    }
+    let mut duplicates: Vec<&Vec<usize>> = positions.values().filter(|p| p.len() > 1).collect();
+    if duplicates.is_empty() {
+        return songs;
+    }
+    duplicates.sort();
+    let kept_position = |p: &Vec<usize>| match policy {
+        DedupePolicy::KeepLast => *p.last().unwrap(),
+        _ => p[0],
+    };
+    for duplicate in &duplicates {
+        let kept = kept_position(duplicate);
+        info!(
+            "{} entries resolved to recording {}:",
+            duplicate.len(),
+            songs[kept].mbid
+        );
+        for &index in duplicate.iter() {
+            let note = match policy {
+                DedupePolicy::Allow => "kept",
+                _ if index == kept => "kept",
+                _ => "removed",
+            };
+            info!("  {:?} ({})", songs[index].file_path, note);
+        }
+    }
+    if matches!(policy, DedupePolicy::Allow) {
+        return songs;
+    }
+    let removed: HashSet<usize> = duplicates
+        .iter()
+        .flat_map(|p| {
+            let kept = kept_position(p);
+            p.iter().copied().filter(move |i| *i != kept)
+        })
+        .collect();
+    info!(
+        "Removed {} repeated entries from the playlist",
+        removed.len()
+    );
+    songs
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !removed.contains(index))
+        .map(|(_, song)| song)
+        .collect()
+}
+
+/// Resolves the tagged songs in batches, giving the MBIDs found by their position in the playlist
+pub async fn resolve_batch_lookups<'a>(
+    listenbrainz_client: &ListenbrainzClient,
+    song_data: impl Iterator<Item = &'a AudioIDData>,
+    concurrency: NonZeroUsize,
+) -> HashMap<usize, Uuid> {
+    let lookups: Vec<(usize, AudioFileData)> = song_data
+        .enumerate()
+        .filter_map(|(index, data)| match data {
+            AudioIDData::AudioFileData(d) | AudioIDData::InferredFileData(d) => {
+                Some((index, d.clone()))
+            }
+            _ => None,
+        })
+        .collect();
+    if lookups.is_empty() {
+        return HashMap::new();
+    }
+
+    debug!("Looking up {} songs in batches", lookups.len());
+    let batch_results: Vec<Vec<(usize, Uuid)>> = stream::iter(lookups.chunks(LOOKUP_BATCH_SIZE))
+        .map(|chunk| async move {
+            let data: Vec<AudioFileData> = chunk.iter().map(|(_, d)| d.clone()).collect();
+            match audio_data::get_musicbrainz_ids_for_audio_data_batch(listenbrainz_client, &data)
+                .await
+            {
+                Ok(mbids) => chunk
+                    .iter()
+                    .zip(mbids)
+                    .filter_map(|((index, _), mbid)| mbid.map(|m| (*index, m)))
+                    .collect(),
+                Err(e) => {
+                    debug!("Batch lookup failed, falling back to single lookups: {}", e);
+                    Vec::new()
+                }
+            }
+        })
+        .buffer_unordered(concurrency.get())
+        .collect()
+        .await;
+    batch_results.into_iter().flatten().collect()
+}
+
+/// Places that know what a file is without reading its tags again
+pub struct KnownFiles {
+    pub beets_library: Option<BeetsLibrary>,
+    pub library_index: Option<LibraryIndex>,
+}
+
+impl KnownFiles {
+    pub fn open(settings: &Config, args: &Args) -> Self {
+        // Unlike for the beets command, beets is only used here if it's been configured
+        let beets_library = settings
+            .get_string("beets_library")
+            .ok()
+            .map(|p| open_beets_library(Path::new(&p)));
+        KnownFiles {
+            beets_library,
+            library_index: open_existing_library_index(settings, args),
+        }
+    }
+
+    pub fn identify(&self, file_path: &Path) -> Option<AudioIDData> {
+        if let Some(item) = self
+            .beets_library
+            .as_ref()
+            .and_then(|b| b.find_by_path(file_path))
+        {
+            return Some(item.audio_id_data());
+        }
+        let indexed = self
+            .library_index
+            .as_ref()
+            .and_then(|i| i.get_if_unchanged(file_path))?;
+        if let Some(mbid) = indexed.recording_mbid {
+            return Some(AudioIDData::Mbid(mbid));
+        }
+        let (Some(artist), Some(title)) = (indexed.artist, indexed.title) else {
+            return None;
+        };
+        Some(AudioIDData::AudioFileData(AudioFileData {
+            artist,
+            title,
+            album: indexed.album.filter(|a| !a.is_empty()),
+        }))
+    }
+}
+
+pub fn identify_file(
+    file_path: &PathBuf,
+    known_files: &KnownFiles,
+    fingerprint: bool,
+    path_patterns: &[PathPattern],
+) -> Option<AudioIDData> {
+    if let Some(data) = known_files.identify(file_path) {
+        return Some(data);
+    }
+    match audio_data::load_tags_from_file_path(file_path.clone()) {
+        Ok(data) => return Some(data),
+        Err(e) => debug!("Could not read tags from {:?}: {}", file_path, e),
+    }
+    if fingerprint {
+        match fingerprint::calculate_fingerprint(file_path) {
+            Ok(f) => return Some(AudioIDData::Fingerprint(f)),
+            Err(e) => error!("Could not fingerprint {:?}: {}", file_path, e),
+        }
+    }
+    path_pattern::infer_from_patterns(path_patterns, file_path).map(AudioIDData::InferredFileData)
+}
+
+/// Combines playlist details from flags, the sidecar file and the M3U header, in that order
+pub fn load_playlist_metadata(args: &Args, file: &PathBuf, read_header: bool) -> PlaylistMetadata {
+    let from_flags = PlaylistMetadata {
+        title: args.playlist_name.clone(),
+        annotation: args.description.clone(),
+        collaborators: args.collaborators.clone(),
+        copied_from: args.copied_from,
+    };
+    let metadata_file = args.metadata_file.clone().unwrap_or_else(|| {
+        let mut path = file.as_os_str().to_owned();
+        path.push(".metadata.json");
+        PathBuf::from(path)
+    });
+    let from_file = if args.metadata_file.is_some() || metadata_file.exists() {
+        debug!("Reading playlist details from {:?}", metadata_file);
+        PlaylistMetadata::from_file(&metadata_file).unwrap_or_else(|e| {
+            error!("Could not read playlist metadata file: {}", e);
+            exit(1)
+        })
+    } else {
+        PlaylistMetadata::default()
+    };
+    let from_header = PlaylistMetadata {
+        title: read_header.then(|| read_m3u_playlist_title(file)).flatten(),
+        ..Default::default()
+    };
+    from_flags.or(from_file).or(from_header)
+}
+
+pub fn read_m3u_playlist_title(file_path: &PathBuf) -> Option<String> {
+    let contents = fs::read_to_string(file_path).ok()?;
+    contents
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("#PLAYLIST:"))
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+}
+
+pub fn annotate_track(annotation: TrackAnnotation, song: &ResolvedSong) -> Option<String> {
+    match annotation {
+        TrackAnnotation::Path => Some(song.file_path.to_string_lossy().to_string()),
+        TrackAnnotation::Comment => audio_data::read_comment_from_metadata(&song.file_path),
+    }
+}
+
+pub fn load_playlist_tracks(file_path: &PathBuf) -> Vec<PlayerTrack> {
+    load_file_paths(file_path)
+        .into_iter()
+        .map(PlayerTrack::from_location)
+        .collect()
+}
+
+pub fn load_file_paths(file_path: &PathBuf) -> Vec<PathBuf> {
+    let playlist_entries: Vec<PathBuf> = m3u::Reader::open(file_path)
+        .expect("Could not read playlist file")
+        .entries()
+        .map(|e| e.expect("Could not read M3U entry"))
+        .filter_map(|e| match e {
+            Entry::Path(path) => Some(path),
+            Entry::Url(_) => None,
+        })
+        .collect();
+    playlist_entries
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_load_songs_from_playlist() {
+        let file_path = &PathBuf::from("./tests/test_playlist_1.m3u");
+        let result = load_file_paths(file_path);
+
+        assert_eq!(result.len(), 4);
+    }
+
+    #[test]
+    fn test_read_playlist_title_from_header() {
+        let file_path = &PathBuf::from("./tests/test_playlist_2.m3u");
+        assert_eq!(
+            read_m3u_playlist_title(file_path),
+            Some("Road Trip".to_string())
+        );
+        assert_eq!(load_file_paths(file_path).len(), 2);
+        assert_eq!(
+            read_m3u_playlist_title(&PathBuf::from("./tests/test_playlist_1.m3u")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_dedupe_resolved_songs() {
+        let first = Uuid::from_str("36855a5c-abcb-4740-9154-361af8c11ee1").unwrap();
+        let second = Uuid::from_str("00066722-b23a-48e5-82e4-0470c82a2705").unwrap();
+        let songs: Vec<ResolvedSong> = [(first, "a"), (second, "b"), (first, "c")]
+            .into_iter()
+            .map(|(mbid, name)| ResolvedSong {
+                file_path: PathBuf::from(name),
+                mbid,
+                source: AudioIDData::Mbid(mbid),
+            })
+            .collect();
+        let paths = |songs: Vec<ResolvedSong>| -> Vec<PathBuf> {
+            songs.into_iter().map(|s| s.file_path).collect()
+        };
+
+        assert_eq!(
+            paths(dedupe_resolved_songs(
+                songs.clone(),
+                DedupePolicy::KeepFirst
+            )),
+            vec![PathBuf::from("a"), PathBuf::from("b")]
+        );
+        assert_eq!(
+            paths(dedupe_resolved_songs(songs.clone(), DedupePolicy::KeepLast)),
+            vec![PathBuf::from("b"), PathBuf::from("c")]
+        );
+        assert_eq!(
+            dedupe_resolved_songs(songs.clone(), DedupePolicy::Allow).len(),
+            3
+        );
+    }
+}